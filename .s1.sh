@@ -7,23 +7,39 @@ echo $HOSTNAME
 DISPLAY=$(ps -u $(id -u) -o pid= | xargs -I{} cat /proc/{}/environ 2>/dev/null | tr '\0' '\n' | grep -m1 '^DISPLAY=' | grep -o '[^=]*$' | cut -d':' -f2)
 XAUTHORITY=$(ps -u $(id -u) -o pid= | xargs -I{} cat /proc/{}/environ 2>/dev/null | tr '\0' '\n' | grep -m1 '^XAUTHORITY=' | grep -o '[^=]*$' | cut -d':' -f2)
 
+WAYLAND_DISPLAY=$(ps -u $(id -u) -o pid= | xargs -I{} cat /proc/{}/environ 2>/dev/null | tr '\0' '\n' | grep -m1 '^WAYLAND_DISPLAY=' | grep -o '[^=]*$')
+
 echo "Display: ${DISPLAY}"
 echo "Xauthority: ${XAUTHORITY}"
+echo "Wayland display: ${WAYLAND_DISPLAY}"
 
-if [ -z "$DISPLAY" ]; then
-    echo "Display variable is empty. Exiting."
-    exit 1
-fi
+if [ -n "$WAYLAND_DISPLAY" ]; then
+    # Wayland sessions are captured through xdg-desktop-portal on the user's session bus. The
+    # capture setting is only read from the system config file, which .s2.sh installs.
+    ENVIRONMENT="DBUS_SESSION_BUS_ADDRESS=unix:path=/run/user/$(id -u)/bus"
+    CAPTURE="portal"
+else
+    if [ -z "$DISPLAY" ]; then
+        echo "Display variable is empty. Exiting."
+        exit 1
+    fi
+
+    xauth generate ":${DISPLAY}" . trusted
+    echo "generated ..."
 
-xauth generate ":${DISPLAY}" . trusted
-echo "generated ..."
+    # Get magic cookie from `xauth list`
+    MAGIC=$(xauth list | head -n 1 | awk '{print $NF}')
 
-# Get magic cookie from `xauth list`
-MAGIC=$(xauth list | head -n 1 | awk '{print $NF}')
+    echo "Magic cookie: ${MAGIC}"
 
-echo "Magic cookie: ${MAGIC}"
+    xauth add ":${DISPLAY}" MIT-MAGIC-COOKIE-1 "$MAGIC"
+
+    ENVIRONMENT="DISPLAY=:${DISPLAY}"
+    # Picked from the environment
+    CAPTURE=""
+fi
 
-xauth add ":${DISPLAY}" MIT-MAGIC-COOKIE-1 "$MAGIC"
+echo "capture = \"${CAPTURE}\"" > ./capture.toml
 
 APPDIR=$(pwd)
 
@@ -34,7 +50,6 @@ SERVICE_USER="${USER}"
 SERVICE_GROUP="${USER}"
 SERVICE_PID_FILE="/var/run/$SERVICE_NAME.pid"
 SERVICE_LOG_FILE="/var/log/$SERVICE_NAME.log"
-ENVIRONMENT="DISPLAY=:${DISPLAY}"
 ENVIRONMENT_FILE="/etc/systemd/system/$SERVICE_NAME.env"
 
 echo "environment: $ENVIRONMENT"
@@ -20,6 +20,18 @@ sudo rm /etc/systemd/system/${SERVICE_NAME}.service
 
 sudo mv ./$SERVICE_NAME.service /etc/systemd/system/$SERVICE_NAME.service
 
+# Settings which decide what is captured are only read from this root-owned file. The capture
+# source picked by .s1.sh replaces any earlier one, keeping the other settings.
+CONFIG_DIR="/etc/$SERVICE_NAME"
+CONFIG_FILE="$CONFIG_DIR/config.toml"
+sudo mkdir -p "$CONFIG_DIR"
+sudo touch "$CONFIG_FILE"
+sudo sed -i '/^capture *=/d' "$CONFIG_FILE"
+cat ./capture.toml | sudo tee -a "$CONFIG_FILE" > /dev/null
+rm ./capture.toml
+sudo chown root:root "$CONFIG_DIR" "$CONFIG_FILE"
+sudo chmod 644 "$CONFIG_FILE"
+
 echo ls -l $DISPLAY
 
 # Remove the device info file if it exists (force a new login)
@@ -0,0 +1,74 @@
+//! Embeds what the daemon was built from, for the attestation module to report.
+//!
+//! `GIT_REVISION` is the commit being built, with `-dirty` appended if the working tree has
+//! uncommitted changes. Builds from a source tarball can set it in the environment instead.
+//! `BUILD_HASH` is a SHA-256 of the manifest and every file under `src/`.
+
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
+
+fn git_revision() -> String {
+    if let Ok(revision) = std::env::var("GIT_REVISION") {
+        return revision;
+    }
+    let Some(revision) = git(&["rev-parse", "HEAD"]) else {
+        return "unknown".to_string();
+    };
+    match git(&["status", "--porcelain", "--untracked-files=no"]) {
+        Some(changes) if changes.is_empty() => revision,
+        _ => format!("{}-dirty", revision),
+    }
+}
+
+/// Every file under `dir`, sorted so that the hash doesn't depend on directory order
+fn files_under(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            files_under(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+fn build_hash() -> String {
+    let mut files = vec![PathBuf::from("Cargo.toml")];
+    files_under(Path::new("src"), &mut files);
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    for file in files {
+        // The path is hashed too, so that moving code between files changes the hash
+        let contents = fs::read(&file).unwrap_or_default();
+        hasher.update(file.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update((contents.len() as u64).to_le_bytes());
+        hasher.update(contents);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=GIT_REVISION");
+
+    println!("cargo:rustc-env=GIT_REVISION={}", git_revision());
+    println!("cargo:rustc-env=BUILD_HASH={}", build_hash());
+}
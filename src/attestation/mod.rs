@@ -0,0 +1,104 @@
+//! Which build of the daemon is running.
+//!
+//! Since the client is open source, anyone can build one which doesn't do its job. The crate
+//! version, git revision and a hash of the source tree are embedded at compile time by
+//! `build.rs`, and the running executable is hashed at startup. All of it is sent with the
+//! registration and every event, so the server can compare the executable's hash with the one
+//! published for that official release and flag devices running anything else.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// The executable of the running process, even if the file has since been replaced or deleted
+const EXE_PATH: &str = "/proc/self/exe";
+
+/// What the running daemon was built from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BuildInfo {
+    /// The crate version
+    pub(crate) version: String,
+    /// The commit built, ending in `-dirty` if there were uncommitted changes
+    pub(crate) git_revision: String,
+    /// SHA-256 of the source tree built
+    pub(crate) build_hash: String,
+    /// SHA-256 of the running executable, if it could be read
+    pub(crate) exe_hash: Option<String>,
+}
+
+impl BuildInfo {
+    fn current() -> Self {
+        let exe_hash = match hash_file(Path::new(EXE_PATH)) {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                error!("Failed to hash the executable: {}", e);
+                None
+            }
+        };
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_revision: env!("GIT_REVISION").to_string(),
+            build_hash: env!("BUILD_HASH").to_string(),
+            exe_hash,
+        }
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "version {} ({}), executable {}",
+            self.version,
+            self.git_revision,
+            self.exe_hash.as_deref().unwrap_or("unknown")
+        )
+    }
+}
+
+/// The build of the running daemon. The executable is hashed on the first call, which should be
+/// made at startup, before anything has had a chance to change it.
+pub(crate) fn build_info() -> &'static BuildInfo {
+    static BUILD_INFO: OnceLock<BuildInfo> = OnceLock::new();
+    BUILD_INFO.get_or_init(BuildInfo::current)
+}
+
+/// SHA-256 of the file at `path`, in hex
+pub(crate) fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_dir;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_hash_file() {
+        let dir = temp_dir();
+        let path = dir.path().join("file");
+        std::fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            hash_file(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.build_hash.len(), 64);
+        // The test binary is the running executable
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!(info.exe_hash, Some(hash_file(&exe).unwrap()));
+    }
+}
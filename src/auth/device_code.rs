@@ -0,0 +1,213 @@
+//! Device-code login, for machines without a browser such as servers or installs over SSH.
+//!
+//! The server hands out a short code, which the user approves by signing in at the printed URL
+//! from any other device. Meanwhile the daemon polls the server until the code is approved,
+//! denied or expires, in the style of the OAuth device authorization grant (RFC 8628).
+
+use crate::auth::register::{SignInFlow, WebLoginCredentials};
+use crate::requests::{
+    DeviceCodeErrorJson, DeviceCodeJson, DeviceCodeRequestJson, DeviceCodeTokenRequestJson,
+};
+use async_trait::async_trait;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use reqwest::{Client, StatusCode};
+use std::error::Error;
+use std::fs;
+use std::io::IsTerminal;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Added to the polling interval each time the server asks the daemon to slow down
+const SLOW_DOWN_INCREMENT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub(crate) enum DeviceCodeError {
+    #[error("Sign-in was denied")]
+    Denied,
+
+    #[error("The sign-in code expired before it was approved")]
+    Expired,
+
+    #[error("Unexpected response while waiting for sign-in: {0}")]
+    Unexpected(String),
+}
+
+/// Signs in by showing a code which the user approves from another device
+pub(crate) struct DeviceCodeSignIn {
+    client: Client,
+    base_url: String,
+}
+
+impl DeviceCodeSignIn {
+    pub(crate) fn new(client: Client, base_url: &str) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Asks the server for a new code, named after this machine
+    async fn request_code(&self) -> Result<DeviceCodeJson, Box<dyn Error + Send + Sync>> {
+        let device_name = fs::read_to_string("/etc/hostname")
+            .map(|name| name.trim().to_string())
+            .unwrap_or_default();
+        let res = self
+            .client
+            .post(self.url("/api/device_code"))
+            .json(&DeviceCodeRequestJson { device_name })
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(format!("Failed to request a sign-in code: {}", res.status()).into());
+        }
+        Ok(res.json().await?)
+    }
+
+    /// Polls the server until the code is approved
+    async fn wait_for_approval(
+        &self,
+        code: &DeviceCodeJson,
+    ) -> Result<WebLoginCredentials, Box<dyn Error + Send + Sync>> {
+        let expires_at = Instant::now() + Duration::from_secs(code.expires_in);
+        let mut interval = Duration::from_secs(code.interval);
+        let body = DeviceCodeTokenRequestJson {
+            device_code: code.device_code.clone(),
+        };
+
+        loop {
+            if Instant::now() >= expires_at {
+                return Err(DeviceCodeError::Expired.into());
+            }
+            tokio::time::sleep(interval).await;
+
+            let res = self
+                .client
+                .post(self.url("/api/device_code/token"))
+                .json(&body)
+                .send()
+                .await?;
+            if res.status() == StatusCode::OK {
+                return Ok(res.json().await?);
+            }
+            let status = res.status();
+            let error = res
+                .json::<DeviceCodeErrorJson>()
+                .await
+                .map(|json| json.error)
+                .unwrap_or_else(|_| status.to_string());
+            match error.as_str() {
+                "authorization_pending" => {}
+                "slow_down" => interval += SLOW_DOWN_INCREMENT,
+                "access_denied" => return Err(DeviceCodeError::Denied.into()),
+                "expired_token" => return Err(DeviceCodeError::Expired.into()),
+                _ => return Err(DeviceCodeError::Unexpected(error).into()),
+            }
+        }
+    }
+}
+
+/// The instructions shown to the user, with a QR code of the verification URL when `qr` is set
+fn instructions(code: &DeviceCodeJson, qr: bool) -> String {
+    let mut text = format!(
+        "To sign in this device, visit {} on any other device and enter the code {}\n",
+        code.verification_uri, code.user_code
+    );
+    if qr {
+        if let Ok(qr_code) = QrCode::new(&code.verification_uri) {
+            text.push_str(&qr_code.render::<Dense1x2>().quiet_zone(true).build());
+            text.push('\n');
+        }
+    }
+    text
+}
+
+#[async_trait]
+impl SignInFlow for DeviceCodeSignIn {
+    async fn sign_in(&self) -> Result<WebLoginCredentials, Box<dyn Error + Send + Sync>> {
+        let code = self.request_code().await?;
+        info!(
+            "Waiting for sign-in code {} to be approved at {}",
+            code.user_code, code.verification_uri
+        );
+        // Printed directly rather than logged, since the user has to see it at the terminal
+        eprint!("{}", instructions(&code, std::io::stderr().is_terminal()));
+
+        self.wait_for_approval(&code).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::requests::mock_server::MockServer;
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    const CODE: &str = r#"{"device_code":"devicecode","user_code":"WDJB-MJHT","verification_uri":"https://example.com/device","interval":0,"expires_in":600}"#;
+
+    /// A server which answers each poll with the next of `answers`, repeating the last one
+    async fn mock_server(answers: &'static [(u16, &'static str)]) -> MockServer {
+        let polls = Arc::new(AtomicU32::new(0));
+        MockServer::start(move |req| match req.path.as_str() {
+            "/api/device_code" => (200, CODE.to_string()),
+            _ => {
+                let poll = polls.fetch_add(1, Ordering::SeqCst) as usize;
+                let (status, body) = answers[poll.min(answers.len() - 1)];
+                (status, body.to_string())
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_sign_in_after_approval() {
+        let server = mock_server(&[
+            (400, r#"{"error":"authorization_pending"}"#),
+            (400, r#"{"error":"authorization_pending"}"#),
+            (200, r#"{"refresh_token":"refresh","device_name":"server"}"#),
+        ])
+        .await;
+        let flow = DeviceCodeSignIn::new(Client::new(), &server.base_url);
+
+        let credentials = flow.sign_in().await.unwrap();
+        assert_eq!(credentials.refresh_token, "refresh");
+        assert_eq!(credentials.device_name, "server");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        let body: DeviceCodeTokenRequestJson = serde_json::from_str(&requests[3].body).unwrap();
+        assert_eq!(body.device_code, "devicecode");
+    }
+
+    #[tokio::test]
+    async fn test_sign_in_denied() {
+        let server = mock_server(&[
+            (400, r#"{"error":"authorization_pending"}"#),
+            (400, r#"{"error":"access_denied"}"#),
+        ])
+        .await;
+        let flow = DeviceCodeSignIn::new(Client::new(), &server.base_url);
+
+        let err = flow.sign_in().await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(DeviceCodeError::Denied)));
+    }
+
+    #[test]
+    fn test_instructions() {
+        let code: DeviceCodeJson = serde_json::from_str(CODE).unwrap();
+        let text = instructions(&code, false);
+        assert_eq!(
+            text,
+            "To sign in this device, visit https://example.com/device on any other device and \
+             enter the code WDJB-MJHT\n"
+        );
+        assert!(instructions(&code, true).lines().count() > 10);
+    }
+}
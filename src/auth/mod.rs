@@ -1,147 +1,517 @@
+pub mod device_code;
 pub mod register;
-
-use crate::auth::register::Device;
-
-use fireauth::FireAuth;
-use log::info;
-use reqwest::{Client, StatusCode};
-
-use crate::SERVICE_NAME;
+mod storage;
+
+use crate::auth::device_code::DeviceCodeSignIn;
+use crate::auth::register::{
+    Device, DeviceFileError, SignInFlow, WebLoginCredentials, WebpageSignIn,
+};
+
+use crate::queue::{EventQueue, QueuedEvent};
+use crate::requests::api::AccountabilityApi;
+use crate::requests::Event;
+use crate::tamper::unit::{check_unit, UnitPaths};
+use crate::tamper::Finding;
+use log::{error, info};
+use reqwest::Client;
+
+use crate::{LEGACY_DEVICE_FILE_NAME, SERVICE_NAME};
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fs;
-use std::io::Read;
-use std::path::Path;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Id tokens are refreshed this long before they expire
+pub(crate) const ID_TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// A failing refresh is tried this many times, waiting twice as long after each failure
+const REFRESH_ATTEMPTS: u32 = 5;
+const REFRESH_INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+
+/// How long to try uploading tamper events before exiting
+const TAMPER_REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Device file used by tests, instead of the configured one
+#[cfg(test)]
+pub(crate) const TEST_DEVICE_INFO_PATH: &str = "./tests/.device";
+
+/// Held by tests which read or write the device file, since they all share one path
+#[cfg(test)]
+pub(crate) static DEVICE_FILE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
 
-#[cfg(not(test))]
-pub const DEVICE_INFO_PATH: &str = "./.device";
+/// An unsigned JWT for the test account which expires at `exp`, in seconds since the Unix epoch
 #[cfg(test)]
-pub const DEVICE_INFO_PATH: &str = "./tests/.device";
+pub(crate) fn test_jwt(exp: u64) -> Token {
+    test_account_jwt("test_account", exp)
+}
+
+/// An unsigned JWT for `account` which expires at `exp`, in seconds since the Unix epoch
+#[cfg(test)]
+pub(crate) fn test_account_jwt(account: &str, exp: u64) -> Token {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    format!(
+        "{}.{}.signature",
+        URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+        URL_SAFE_NO_PAD.encode(format!(r#"{{"sub":"{}","exp":{}}}"#, account, exp))
+    )
+}
 
 pub type Token = String;
 
+#[derive(Error, Debug)]
+pub(crate) enum AuthError {
+    #[error("Failed to refresh the id token after {attempts} attempts: {last_error}")]
+    RefreshFailed { attempts: u32, last_error: String },
+
+    #[error("No active subscription")]
+    SubscriptionLapsed,
+
+    #[error("Service unit has been tampered with")]
+    TamperFlagged,
+}
+
+/// Where the device stands with the server.
+///
+/// Each state other than [`AuthState::Registered`] has a recovery action, run by
+/// [`Auth::recover`]:
+///
+/// | State                | Recovery                          | Success        | Failure            |
+/// |----------------------|-----------------------------------|----------------|--------------------|
+/// | `Unregistered`       | register, once signed in          | `Registered`   | `Revoked`, or stay |
+/// | `TokenExpired`       | refresh the id token with backoff | `Registered`   | `Revoked`          |
+/// | `Revoked`            | sign in again                     | `TokenExpired` | stay               |
+/// | `SubscriptionLapsed` | ask the server again              | `Registered`   | stay               |
+/// | `TamperFlagged`      | none, the program exits           |                |                    |
+///
+/// A device without a refresh token is `Revoked` until the user signs in, and one which was just
+/// signed in goes through `TokenExpired` and `Unregistered`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AuthState {
+    /// There is no device file, or the server no longer knows the device
+    Unregistered,
+    /// The id token is valid, or can be refreshed when it expires
+    Registered,
+    /// The id token has expired, or is about to
+    TokenExpired,
+    /// The refresh token no longer works, so the user has to sign in again. Events are queued
+    /// until then.
+    Revoked,
+    /// The user's subscription has lapsed. Events are queued until it is renewed.
+    SubscriptionLapsed,
+    /// The service unit was tampered with, and the device was reported as exiting
+    TamperFlagged,
+}
+
+/// Chooses how the user signs in: with a device code if `device_code` is set or there is no
+/// display to open a browser on, and through the local web page otherwise
+pub(crate) fn sign_in_flow(
+    device_code: bool,
+    client: Client,
+    base_url: &str,
+) -> Arc<dyn SignInFlow> {
+    let has_display = ["DISPLAY", "WAYLAND_DISPLAY"]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some_and(|value| !value.is_empty()));
+    if device_code || !has_display {
+        Arc::new(DeviceCodeSignIn::new(client, base_url))
+    } else {
+        Arc::new(WebpageSignIn)
+    }
+}
+
+/// Reads the device file, first moving it from where older versions kept it if needed
+fn load_device(device_path: &Path) -> Result<Device, Box<dyn Error>> {
+    let legacy_path = std::env::current_exe()?.with_file_name(LEGACY_DEVICE_FILE_NAME);
+    if !device_path.exists() && legacy_path.exists() && legacy_path != device_path {
+        info!(
+            "Moving the device file from {:?} to {:?}",
+            legacy_path, device_path
+        );
+        Device::from_file(&legacy_path)?.write_device_info(device_path, true)?;
+        fs::remove_file(&legacy_path)?;
+    }
+    Ok(Device::from_file(device_path)?)
+}
+
+/// Moves a corrupt device file out of the way, keeping it for inspection. Returns where it was
+/// moved to.
+fn quarantine_device_file(device_path: &Path) -> std::io::Result<PathBuf> {
+    let mut name = device_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".corrupt");
+    let quarantine_path = device_path.with_file_name(name);
+    match fs::rename(device_path, &quarantine_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(quarantine_path),
+    }
+}
+
+/// Calls `refresh` until it returns a new id token, backing off exponentially between attempts
+pub(crate) async fn refresh_with_backoff<F, Fut>(mut refresh: F) -> Result<Token, AuthError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Token, Box<dyn Error>>>,
+{
+    let mut backoff = REFRESH_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let last_error = match refresh().await {
+            Ok(token) => return Ok(token),
+            Err(e) => e.to_string(),
+        };
+        if attempt == REFRESH_ATTEMPTS {
+            return Err(AuthError::RefreshFailed {
+                attempts: attempt,
+                last_error,
+            });
+        }
+        warn!(
+            "Failed to refresh the id token (attempt {}), retrying in {:?}: {}",
+            attempt, backoff, last_error
+        );
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
 #[derive(Serialize, Debug, Deserialize, Clone)]
 pub struct TokenReqBody {
     pub(crate) id_token: String,
     pub(crate) device_id: String,
 }
 
-#[derive(Serialize, Debug, Deserialize)]
-pub struct TokenFile {
-    refresh_token: String,
-    device_id: String,
-}
-
 pub(crate) struct Auth {
-    pub(crate) fire_auth: FireAuth,
-    pub(crate) client: Client,
+    pub(crate) api: Arc<dyn AccountabilityApi>,
     pub(crate) device: Device,
+    /// Where the device's credentials are stored between runs
+    pub(crate) device_path: PathBuf,
+    pub(crate) state: AuthState,
+    pub(crate) sign_in_flow: Arc<dyn SignInFlow>,
+    /// A sign-in started in the background while the monitor keeps running
+    pub(crate) pending_sign_in: Option<JoinHandle<SignInResult>>,
+    /// The account the device was signed in to before signing in again, until the new id token
+    /// shows which account the user signed in to
+    pub(crate) previous_account: Option<String>,
+    /// Publishes the device's credentials whenever they change, for the uploader
+    pub(crate) credentials: watch::Sender<Device>,
+    /// Events found while starting up, to be queued once the event queue is open
+    pub(crate) startup_events: Vec<QueuedEvent>,
 }
 
+type SignInResult = Result<WebLoginCredentials, Box<dyn Error + Send + Sync>>;
+
 impl Auth {
     /// Creates a new authenticated session. Does not return until successful authentication is
-    /// achieved.
-    pub async fn new(_client: Client) -> Result<Self, Box<dyn Error>> {
-        let api_key: String = dotenv!("API_KEY").to_string();
-        let fire_auth = fireauth::FireAuth::new(api_key);
-        let client = Client::new();
-
-        let mut device;
-        if Path::new(DEVICE_INFO_PATH).exists() {
-            device = Device::from_file(&fire_auth).await?;
+    /// achieved, signing in through `sign_in_flow` if needed.
+    pub async fn new(
+        api: Arc<dyn AccountabilityApi>,
+        device_path: PathBuf,
+        sign_in_flow: Arc<dyn SignInFlow>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut startup_events = Vec::new();
+        let (device, state) = match load_device(&device_path) {
+            Ok(device) => (device, AuthState::TokenExpired),
+            Err(e) => match e.downcast_ref() {
+                Some(DeviceFileError::Io(io)) if io.kind() == std::io::ErrorKind::NotFound => {
+                    (Device::default(), AuthState::Unregistered)
+                }
+                Some(DeviceFileError::Corrupt(reason)) => {
+                    let quarantine_path = quarantine_device_file(&device_path)?;
+                    error!("{}, moved it to {:?}", e, quarantine_path);
+                    // Printed directly rather than logged, since the user has to act on it
+                    eprintln!(
+                        "The device file at {:?} could not be read, so this device has to be \
+                         registered again. Sign in to continue.",
+                        device_path
+                    );
+                    startup_events.push(QueuedEvent::new(
+                        SystemTime::now(),
+                        Event::DeviceFileCorrupt {
+                            reason: reason.clone(),
+                        },
+                    ));
+                    (Device::default(), AuthState::Unregistered)
+                }
+                _ => return Err(e),
+            },
+        };
+        let mut auth = Self {
+            api,
+            credentials: watch::channel(device.clone()).0,
+            device,
+            device_path,
+            state,
+            sign_in_flow,
+            pending_sign_in: None,
+            previous_account: None,
+            startup_events,
+        };
+        auth.authenticate().await?;
 
+        if auth.state == AuthState::Registered {
             // Check the safe_exit_id
-            if device
-                .check_safe_exit_id(&fire_auth, &client)
-                .await
-                .is_err()
-            {
-                eprintln!("safe exit id is error");
-                match device.register_device(&fire_auth, &client).await {
-                    Ok(res) if res.status() == StatusCode::OK => {
-                        info!("Successfully registered device");
-                        device.uuid = res.text().await?;
+            if let Err(e) = auth.device.check_safe_exit_id(auth.api.as_ref()).await {
+                warn!("Safe exit id was not accepted, registering again: {}", e);
+                auth.transition(AuthState::Unregistered);
+                auth.authenticate().await?;
+            }
+            auth.device.get_safe_exit_id(auth.api.as_ref()).await?;
+            auth.device.write_device_info(&auth.device_path, false)?;
+            // Stored by the power monitor if the machine shuts down
+            auth.publish_credentials();
+        }
+        Ok(auth)
+    }
+
+    fn transition(&mut self, state: AuthState) {
+        if self.state != state {
+            info!("Authentication state: {:?} -> {:?}", self.state, state);
+            self.state = state;
+        }
+    }
+
+    /// Moves to the state an error from the server calls for, if any
+    fn observe_error(&mut self, e: &(dyn Error + 'static)) {
+        match e.downcast_ref() {
+            Some(AuthError::RefreshFailed { .. }) => self.transition(AuthState::Revoked),
+            Some(AuthError::SubscriptionLapsed) => self.transition(AuthState::SubscriptionLapsed),
+            _ => {}
+        }
+    }
+
+    /// Runs recovery actions, signing in interactively, until the device is registered. A lapsed
+    /// subscription doesn't stop the program, since events are queued until it is renewed.
+    pub(crate) async fn authenticate(&mut self) -> Result<(), Box<dyn Error>> {
+        while !matches!(
+            self.state,
+            AuthState::Registered | AuthState::SubscriptionLapsed
+        ) {
+            self.recover(true).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs the recovery action of the current state once, and returns the new state.
+    ///
+    /// Unless `interactive`, signing in is started in the background and picked up by a later
+    /// call, so that monitoring carries on while the user signs in. Errors which don't change
+    /// the state, such as the server being unreachable, are returned.
+    pub(crate) async fn recover(&mut self, interactive: bool) -> Result<AuthState, Box<dyn Error>> {
+        match self.state {
+            AuthState::Registered => {}
+            AuthState::Unregistered => {
+                if self.device.refresh_token.is_empty() {
+                    self.transition(AuthState::Revoked);
+                    return Ok(self.state);
+                }
+                if let Err(e) = self.device.register_device(self.api.as_ref()).await {
+                    self.observe_error(e.as_ref());
+                    if self.state == AuthState::Unregistered {
+                        return Err(e);
+                    }
+                    return Ok(self.state);
+                }
+                self.device.write_device_info(&self.device_path, false)?;
+                self.transition(AuthState::Registered);
+            }
+            AuthState::TokenExpired => {
+                match self.device.refresh_id_token(self.api.as_ref()).await {
+                    Ok(()) if self.signed_in_to_other_account() => {
+                        warn!("Signed in to a different account, registering this device again");
+                        self.device.uuid.clear();
+                        self.device.safe_shutdown_id.clear();
+                        self.transition(AuthState::Unregistered);
                     }
-                    a => {
-                        dbg!(a).unwrap();
+                    // A device which was only just signed in still has to be registered
+                    Ok(()) if self.device.uuid.is_empty() => {
+                        self.transition(AuthState::Unregistered)
+                    }
+                    Ok(()) => self.transition(AuthState::Registered),
+                    Err(e) => {
+                        error!("{}. Sign in again to resume uploading events", e);
+                        self.transition(AuthState::Revoked);
                     }
                 }
             }
-        } else {
-            device = Device::new(&fire_auth, &client).await?;
+            AuthState::Revoked => {
+                let credentials = if interactive {
+                    Some(self.sign_in_flow.sign_in().await)
+                } else {
+                    self.poll_background_sign_in().await?
+                };
+                if let Some(credentials) = credentials {
+                    // Kept from an earlier sign-in whose id token was never refreshed
+                    if let Some(account) = self.device.account_id() {
+                        self.previous_account = Some(account);
+                    }
+                    self.device.sign_in(credentials.map_err(|e| e.to_string())?);
+                    info!("Signed in again");
+                    self.transition(AuthState::TokenExpired);
+                }
+            }
+            AuthState::SubscriptionLapsed => {
+                // Any answer other than the subscription error means it was renewed
+                if let Err(e) = self.api.check_safe_exit_id(&mut self.device).await {
+                    if !matches!(e.downcast_ref(), Some(AuthError::SubscriptionLapsed)) {
+                        return Err(e);
+                    }
+                } else {
+                    self.transition(AuthState::Registered);
+                }
+            }
+            AuthState::TamperFlagged => return Err(AuthError::TamperFlagged.into()),
         }
 
-        device.get_safe_exit_id(&fire_auth, &client).await?;
+        self.publish_credentials();
+        Ok(self.state)
+    }
 
-        device.write_device_info(false)?;
+    /// Whether the id token refreshed after signing in again belongs to another account than
+    /// the device was registered to
+    fn signed_in_to_other_account(&mut self) -> bool {
+        self.previous_account
+            .take()
+            .is_some_and(|previous| self.device.account_id() != Some(previous))
+    }
 
-        Ok(Self {
-            fire_auth,
-            client,
-            device,
-        })
+    /// Publishes the device's credentials, if they changed since they were last published
+    fn publish_credentials(&self) {
+        self.credentials.send_if_modified(|published| {
+            if *published == self.device {
+                return false;
+            }
+            *published = self.device.clone();
+            true
+        });
+    }
+
+    /// Starts signing in in the background, or returns the result once the user has signed in
+    async fn poll_background_sign_in(&mut self) -> Result<Option<SignInResult>, Box<dyn Error>> {
+        match self.pending_sign_in.take() {
+            Some(handle) if handle.is_finished() => Ok(Some(handle.await?)),
+            Some(handle) => {
+                self.pending_sign_in = Some(handle);
+                Ok(None)
+            }
+            None => {
+                warn!("Signing in again in the background");
+                let flow = self.sign_in_flow.clone();
+                self.pending_sign_in = Some(tokio::spawn(async move { flow.sign_in().await }));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Keeps the device authenticated between captures: refreshes the id token before it
+    /// expires, and runs the recovery action of any other state without blocking on the user.
+    pub(crate) async fn maintain(&mut self) -> AuthState {
+        if self.state == AuthState::Registered
+            && self.device.id_token_expires_within(ID_TOKEN_REFRESH_MARGIN)
+        {
+            self.transition(AuthState::TokenExpired);
+        }
+        if let Err(e) = self.recover(false).await {
+            warn!("Failed to recover from {:?}: {}", self.state, e);
+        }
+        self.state
+    }
+
+    /// Receives the device's credentials whenever they change
+    pub(crate) fn subscribe(&self) -> watch::Receiver<Device> {
+        self.credentials.subscribe()
     }
 
     pub(crate) async fn exit_program(
-        &self,
+        &mut self,
         shutdown_in_progress: bool,
     ) -> Result<(), Box<dyn Error>> {
         if !shutdown_in_progress {
             info!("System is not in shut-down procedure. Posting exit event to server.");
-            self.client
-                .patch("https://us-central1-openaccountability.cloudfunctions.net/api/device")
-                .bearer_auth(self.device.refresh_token.clone())
-                .json(&TokenReqBody {
-                    id_token: self.device.id_token.clone(),
-                    device_id: self.device.uuid.clone(),
-                })
-                .send()
-                .await?;
-            fs::remove_file(DEVICE_INFO_PATH)?;
+            self.api.report_exit(&mut self.device).await?;
+            fs::remove_file(&self.device_path)?;
         } else {
-            self.device.write_device_info(true)?;
+            self.device.write_device_info(&self.device_path, true)?;
         }
         info!("Exiting program");
         Ok(())
     }
 
-    pub(crate) async fn check_service_file(&self) -> Result<(), Box<dyn Error>> {
-        // Check that the .service file hasn't been modified
-        let service_file_path = "/etc/systemd/system/".to_string() + SERVICE_NAME + ".service";
-        // Read the file into a string
-        let mut service_file = std::fs::File::open(service_file_path)?;
-        let mut service_file_contents = String::new();
-        service_file.read_to_string(&mut service_file_contents)?;
-        eprintln!("{}", service_file_contents);
-        // Ensure that the Restart line is correct
-        // Ensure that "Restart" appears exactly once
-
-        let mut restart = false;
-        let mut restart_sec = false;
-        for line in service_file_contents.lines() {
-            match line {
-                "Restart=always" => {
-                    restart = true;
-                }
-                l if l.contains("Restart=") => {
-                    restart = false;
-                }
-                "RestartSec=30s" => {
-                    restart_sec = true;
-                }
-                l if l.contains("RestartSec=") => {
-                    restart_sec = false;
+    /// Checks that the service unit still keeps the daemon running. Each problem is queued as a
+    /// tamper event before the device is flagged and the program exits.
+    pub(crate) async fn check_service_file(
+        &mut self,
+        queue: &EventQueue,
+    ) -> Result<(), Box<dyn Error>> {
+        let unit = format!("{}.service", SERVICE_NAME);
+        let findings = check_unit(&UnitPaths::system(), &unit, &std::env::current_exe()?);
+        if findings.is_empty() {
+            return Ok(());
+        }
+        self.flag_tampering(findings, queue).await
+    }
+
+    /// Queues a tamper event for each finding, then flags the device and exits. The device only
+    /// reports its exit once every queued event has been uploaded, since that removes its
+    /// credentials; otherwise the events stay queued for the next run to upload.
+    async fn flag_tampering(
+        &mut self,
+        findings: Vec<Finding>,
+        queue: &EventQueue,
+    ) -> Result<(), Box<dyn Error>> {
+        for finding in findings {
+            error!("Service unit has been tampered with: {}", finding);
+            queue.push(&QueuedEvent::new(SystemTime::now(), finding.into_event()))?;
+        }
+        self.transition(AuthState::TamperFlagged);
+
+        match tokio::time::timeout(
+            TAMPER_REPORT_TIMEOUT,
+            queue.drain(self.api.as_ref(), &mut self.device),
+        )
+        .await
+        {
+            Ok(Ok(_)) => {
+                if let Err(e) = self.exit_program(false).await {
+                    warn!("Failed to report exit: {}", e);
                 }
-                _ => {}
             }
+            Ok(Err(e)) => warn!("Failed to report tampering, keeping it queued: {}", e),
+            Err(_) => warn!(
+                "Gave up reporting tampering after {:?}, keeping it queued",
+                TAMPER_REPORT_TIMEOUT
+            ),
         }
+        Err(AuthError::TamperFlagged.into())
+    }
+}
 
-        if !(restart && restart_sec) {
-            // The service file has been tampered with! Terminate the device
-            let _ = self.exit_program(false).await;
-            return Err(Box::try_from("Service file has been tampered with").unwrap());
+#[cfg(test)]
+impl Auth {
+    /// A registered session for `device`, which never touches the device file
+    pub(crate) fn registered(
+        api: Arc<dyn AccountabilityApi>,
+        device: Device,
+        sign_in_flow: Arc<dyn SignInFlow>,
+    ) -> Self {
+        Self {
+            api,
+            credentials: watch::channel(device.clone()).0,
+            device,
+            device_path: TEST_DEVICE_INFO_PATH.into(),
+            state: AuthState::Registered,
+            sign_in_flow,
+            pending_sign_in: None,
+            previous_account: None,
+            startup_events: Vec::new(),
         }
-        Ok(())
     }
 }
 
@@ -149,52 +519,253 @@ impl Auth {
 mod tests {
     use super::*;
 
+    use crate::auth::register::MockSignInFlow;
+    use crate::requests::api::MockAccountabilityApi;
+    use crate::tamper::TamperKind;
+    use crate::test_util::{temp_dir, test_device};
     use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
 
-    fn create_device_info_file_from_env() {
+    const TEST_REFRESH_TOKEN: &str = "test_refresh_token";
+    const TEST_DEVICE_UID: &str = "test_device_uid";
+
+    fn create_device_info_file() {
         let device = Device {
-            refresh_token: dotenv!("TEST_REFRESH_TOKEN").to_string(),
             id_token: "".to_string(),
-            uuid: dotenv!("TEST_DEVICE_UID").to_string(),
-            name: "".to_string(),
-            safe_shutdown_id: "".to_string(),
+            ..test_device()
         };
-        device.write_device_info(false).unwrap();
+        device
+            .write_device_info(Path::new(TEST_DEVICE_INFO_PATH), false)
+            .unwrap();
+    }
+
+    /// A mock server which only accepts `safe_exit_id` as the device's safe exit id, and
+    /// registers devices under `new_device_uid`
+    fn mock_api(safe_exit_id: &'static str) -> MockAccountabilityApi {
+        let mut api = MockAccountabilityApi::new();
+        api.expect_refresh_id_token()
+            .returning(|_| Ok("test_id_token".to_string()));
+        api.expect_check_safe_exit_id()
+            .returning(move |device| Ok(device.safe_shutdown_id == safe_exit_id));
+        api.expect_register_device()
+            .returning(|_| Ok("new_device_uid".to_string()));
+        api.expect_get_safe_exit_id()
+            .returning(move |_| Ok(safe_exit_id.to_string()));
+        api
     }
 
     /// Test re-registering a device
     #[tokio::test]
     async fn test_new_auth() {
-        create_device_info_file_from_env();
-        let client = Client::new();
-        let auth = Auth::new(client).await.unwrap();
+        let _lock = DEVICE_FILE_LOCK.lock().await;
+        create_device_info_file();
+        let auth = Auth::new(
+            Arc::new(mock_api("testexitid123")),
+            TEST_DEVICE_INFO_PATH.into(),
+            Arc::new(MockSignInFlow::new()),
+        )
+        .await
+        .unwrap();
         assert!(!auth.device.safe_shutdown_id.is_empty());
-        assert_ne!(auth.device.uuid, dotenv!("TEST_DEVICE_UID").to_string());
+        assert_ne!(auth.device.uuid, TEST_DEVICE_UID.to_string());
+        assert_eq!(auth.device.refresh_token, TEST_REFRESH_TOKEN.to_string());
+        assert_eq!(auth.state, AuthState::Registered);
+    }
+
+    /// A mock server whose refresh token is `refresh_token`, handing out id tokens which are
+    /// valid for an hour
+    fn expiring_token_api(
+        refresh_token: &'static str,
+        attempts: Arc<AtomicU32>,
+    ) -> MockAccountabilityApi {
+        let mut api = MockAccountabilityApi::new();
+        api.expect_refresh_id_token().returning(move |token| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            if token == refresh_token {
+                Ok(test_jwt(now() + 60 * 60))
+            } else {
+                Err("INVALID_REFRESH_TOKEN".into())
+            }
+        });
+        api.expect_register_device()
+            .returning(|_| Ok("new_device_uid".to_string()));
+        api.expect_get_safe_exit_id()
+            .returning(|_| Ok("testexitid123".to_string()));
+        api
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn sign_in_as(refresh_token: &'static str) -> MockSignInFlow {
+        let mut flow = MockSignInFlow::new();
+        flow.expect_sign_in().times(1).returning(move || {
+            Ok(WebLoginCredentials {
+                refresh_token: refresh_token.to_string(),
+                device_name: "laptop".to_string(),
+            })
+        });
+        flow
+    }
+
+    /// A revoked refresh token signs in again in the background, without stopping the monitor
+    #[tokio::test(start_paused = true)]
+    async fn test_revoked_token_signs_in_again() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let api = expiring_token_api("new_refresh_token", attempts.clone());
+        let device = Device {
+            id_token: test_jwt(now() + 60 * 60),
+            ..test_device()
+        };
+        let mut auth = Auth::registered(
+            Arc::new(api),
+            device,
+            Arc::new(sign_in_as("new_refresh_token")),
+        );
+        let mut credentials = auth.subscribe();
+
+        // A token which isn't about to expire is left alone
+        assert_eq!(auth.maintain().await, AuthState::Registered);
+        assert_eq!(attempts.load(Ordering::SeqCst), 0);
+
+        auth.device.id_token = test_jwt(now() + 60);
+        assert_eq!(auth.maintain().await, AuthState::Revoked);
+        assert_eq!(attempts.load(Ordering::SeqCst), REFRESH_ATTEMPTS);
+
+        // The sign-in runs in the background until the user finishes it
+        assert_eq!(auth.maintain().await, AuthState::Revoked);
+        while auth.maintain().await == AuthState::Revoked {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(auth.state, AuthState::TokenExpired);
+        assert_eq!(auth.maintain().await, AuthState::Registered);
+        assert_eq!(auth.device.uuid, TEST_DEVICE_UID);
+
+        // The uploader picks up the new credentials
+        assert!(credentials.has_changed().unwrap());
         assert_eq!(
-            auth.device.refresh_token,
-            dotenv!("TEST_REFRESH_TOKEN").to_string()
+            credentials.borrow_and_update().refresh_token,
+            "new_refresh_token"
         );
-        // assert_eq!(auth.state, AuthState::Authenticated("".to_string()));
     }
 
+    /// Signing in to another account registers the device to that account instead of keeping
+    /// the old account's device
+    #[tokio::test(start_paused = true)]
+    async fn test_sign_in_to_other_account_registers_again() {
+        let api = expiring_token_api("new_refresh_token", Arc::new(AtomicU32::new(0)));
+        let device = Device {
+            id_token: test_account_jwt("other_account", now() + 60),
+            ..test_device()
+        };
+        let mut auth = Auth::registered(
+            Arc::new(api),
+            device,
+            Arc::new(sign_in_as("new_refresh_token")),
+        );
+        let device_dir = temp_dir();
+        auth.device_path = device_dir.path().join("device");
+        let mut credentials = auth.subscribe();
+
+        while auth.maintain().await != AuthState::TokenExpired {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(auth.maintain().await, AuthState::Unregistered);
+        assert_eq!(auth.device.uuid, "");
+        assert_eq!(auth.device.account_id().unwrap(), "test_account");
+        credentials.mark_unchanged();
+
+        assert_eq!(auth.maintain().await, AuthState::Registered);
+        assert_eq!(auth.device.uuid, "new_device_uid");
+        assert_eq!(credentials.borrow_and_update().uuid, "new_device_uid");
+
+        // Nothing changed, so the uploader isn't woken up
+        auth.maintain().await;
+        assert!(!credentials.has_changed().unwrap());
+    }
+
+    /// A device without a device file signs in and registers before starting
     #[tokio::test]
-    async fn test_exit_program_safe() {
-        create_device_info_file_from_env();
-        let client = Client::new();
-        let auth = Auth::new(client).await.unwrap();
-        assert!(!auth.device.safe_shutdown_id.is_empty());
-        assert_ne!(auth.device.uuid, dotenv!("TEST_DEVICE_UID").to_string());
+    async fn test_new_device_signs_in() {
+        let device_dir = temp_dir();
+        let device_path = device_dir.path().join("device");
+        let mut api = expiring_token_api("new_refresh_token", Arc::new(AtomicU32::new(0)));
+        api.expect_check_safe_exit_id().returning(|_| Ok(true));
+
+        let auth = Auth::new(
+            Arc::new(api),
+            device_path.clone(),
+            Arc::new(sign_in_as("new_refresh_token")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(auth.state, AuthState::Registered);
+        assert_eq!(auth.device.uuid, "new_device_uid");
+        assert_eq!(auth.device.name, "laptop");
+        assert_eq!(
+            Device::from_file(&device_path).unwrap().refresh_token,
+            "new_refresh_token"
+        );
+    }
+
+    /// A device file which can't be read is reported and set aside, and the device signs in and
+    /// registers again instead of failing to start
+    #[tokio::test]
+    async fn test_corrupt_device_file_registers_again() {
+        let device_dir = temp_dir();
+        let device_path = device_dir.path().join("device");
+        fs::write(&device_path, r#"{"refresh_token":"trunc"#).unwrap();
+        let mut api = expiring_token_api("new_refresh_token", Arc::new(AtomicU32::new(0)));
+        api.expect_check_safe_exit_id().returning(|_| Ok(true));
+
+        let auth = Auth::new(
+            Arc::new(api),
+            device_path.clone(),
+            Arc::new(sign_in_as("new_refresh_token")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(auth.state, AuthState::Registered);
+        assert_eq!(auth.device.uuid, "new_device_uid");
+        assert_eq!(auth.startup_events.len(), 1);
+        assert!(matches!(
+            auth.startup_events[0].event,
+            Event::DeviceFileCorrupt { .. }
+        ));
+        assert_eq!(
+            fs::read_to_string(device_dir.path().join("device.corrupt")).unwrap(),
+            r#"{"refresh_token":"trunc"#
+        );
         assert_eq!(
-            auth.device.refresh_token,
-            dotenv!("TEST_REFRESH_TOKEN").to_string()
+            Device::from_file(&device_path).unwrap().refresh_token,
+            "new_refresh_token"
         );
+    }
+
+    #[tokio::test]
+    async fn test_exit_program_safe() {
+        let _lock = DEVICE_FILE_LOCK.lock().await;
+        create_device_info_file();
+        let mut auth = Auth::new(
+            Arc::new(mock_api("testexitid123")),
+            TEST_DEVICE_INFO_PATH.into(),
+            Arc::new(MockSignInFlow::new()),
+        )
+        .await
+        .unwrap();
+        assert!(!auth.device.safe_shutdown_id.is_empty());
+        assert_ne!(auth.device.uuid, TEST_DEVICE_UID.to_string());
+        assert_eq!(auth.device.refresh_token, TEST_REFRESH_TOKEN.to_string());
 
         let device_copy = auth.device.clone();
 
         auth.exit_program(true).await.unwrap();
-        let contents = fs::read_to_string(DEVICE_INFO_PATH).unwrap();
-
-        let device: Device = serde_json::from_str(&contents).unwrap();
+        let device = Device::from_file(Path::new(TEST_DEVICE_INFO_PATH)).unwrap();
 
         assert_eq!(
             device.safe_shutdown_id,
@@ -202,31 +773,90 @@ mod tests {
         );
 
         // Re-authenticate and make sure the device id is the same
-        let client = Client::new();
-        let auth = Auth::new(client).await.unwrap();
+        let mut api = MockAccountabilityApi::new();
+        api.expect_refresh_id_token()
+            .returning(|_| Ok("test_id_token".to_string()));
+        api.expect_check_safe_exit_id()
+            .returning(|device| Ok(device.safe_shutdown_id == "testexitid123"));
+        api.expect_register_device().never();
+        api.expect_get_safe_exit_id()
+            .returning(|_| Ok("testexitid456".to_string()));
+        let auth = Auth::new(
+            Arc::new(api),
+            TEST_DEVICE_INFO_PATH.into(),
+            Arc::new(MockSignInFlow::new()),
+        )
+        .await
+        .unwrap();
         assert_eq!(auth.device.uuid, device_copy.uuid.clone());
-
-        // Below isn't true for this test, since the shutdown id is hardcoded for the test scenario
-        // assert_ne!(auth.device.safe_shutdown_id, device_copy.safe_shutdown_id);
+        assert_ne!(auth.device.safe_shutdown_id, device_copy.safe_shutdown_id);
     }
 
     #[tokio::test]
     async fn test_exit_program_unsafe() {
-        create_device_info_file_from_env();
-        let client = Client::new();
-        let auth = Auth::new(client).await.unwrap();
+        let _lock = DEVICE_FILE_LOCK.lock().await;
+        create_device_info_file();
+        let mut api = mock_api("testexitid123");
+        api.expect_report_exit()
+            .times(1)
+            .withf(|device| device.uuid == "new_device_uid")
+            .returning(|_| Ok(()));
+        let mut auth = Auth::new(
+            Arc::new(api),
+            TEST_DEVICE_INFO_PATH.into(),
+            Arc::new(MockSignInFlow::new()),
+        )
+        .await
+        .unwrap();
         assert!(!auth.device.safe_shutdown_id.is_empty());
-        assert_ne!(auth.device.uuid, dotenv!("TEST_DEVICE_UID").to_string());
-        assert_eq!(
-            auth.device.refresh_token,
-            dotenv!("TEST_REFRESH_TOKEN").to_string()
-        );
+        assert_ne!(auth.device.uuid, TEST_DEVICE_UID.to_string());
+        assert_eq!(auth.device.refresh_token, TEST_REFRESH_TOKEN.to_string());
 
         let _exit_id_copy = auth.device.safe_shutdown_id.clone();
 
         auth.exit_program(false).await.unwrap();
 
         // Check that the device file is deleted
-        assert!(fs::metadata(DEVICE_INFO_PATH).is_err());
+        assert!(fs::metadata(TEST_DEVICE_INFO_PATH).is_err());
+    }
+
+    /// Tamper events are queued, and the device only reports its exit once they're uploaded
+    #[tokio::test]
+    async fn test_tampering_is_queued_until_uploaded() {
+        let dir = temp_dir();
+        let queue = EventQueue::open(dir.path().join("events")).unwrap();
+        let finding = || vec![Finding::new(TamperKind::UnitDisabled, "not enabled")];
+
+        // Offline: the event stays queued, and the credentials are kept for the next run
+        let mut api = MockAccountabilityApi::new();
+        api.expect_post_event()
+            .returning(|_, _, _| Err("offline".into()));
+        api.expect_report_exit().never();
+        let mut auth = Auth::registered(
+            Arc::new(api),
+            test_device(),
+            Arc::new(MockSignInFlow::new()),
+        );
+        assert!(auth.flag_tampering(finding(), &queue).await.is_err());
+        assert_eq!(auth.state, AuthState::TamperFlagged);
+        assert_eq!(queue.pending().unwrap().len(), 1);
+
+        // Online: the queued events are uploaded before the exit is reported
+        let mut api = MockAccountabilityApi::new();
+        api.expect_post_event()
+            .times(2)
+            .withf(|_, _, event| matches!(event, Event::Tamper { .. }))
+            .returning(|_, _, _| Ok(()));
+        api.expect_report_exit().times(1).returning(|_| Ok(()));
+        let mut auth = Auth::registered(
+            Arc::new(api),
+            test_device(),
+            Arc::new(MockSignInFlow::new()),
+        );
+        auth.device_path = dir.path().join("device");
+        fs::write(&auth.device_path, "").unwrap();
+        assert!(auth.flag_tampering(finding(), &queue).await.is_err());
+        assert!(queue.pending().unwrap().is_empty());
+        assert!(!auth.device_path.exists());
     }
 }
@@ -1,33 +1,88 @@
-use crate::auth::{Token, DEVICE_INFO_PATH};
-use crate::requests::{
-    make_request_with_id_token, refresh_id_token, CheckSafeExitIdJson, DeviceRegisterJson,
-    GetSafeExitIdJson,
-};
-use fireauth::FireAuth;
+use crate::auth::storage::{self, StorageError};
+use crate::auth::{refresh_with_backoff, AuthError, Token};
+use crate::requests::api::AccountabilityApi;
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+#[cfg(test)]
+use mockall::automock;
 use std::borrow::Cow;
 
-use reqwest::{Client, Response, StatusCode};
-use rocket::http::ContentType;
+use rocket::config::{LogLevel, Shutdown};
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
 use rocket::response::content::RawHtml;
 use rocket::serde::json::Json;
 use rocket::{Build, Rocket, State};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::error::Error;
 use std::ffi::OsStr;
 use std::fs;
-use std::path::PathBuf;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
 
+/// What the user enters when signing the device in
 #[derive(Serialize, Debug, Deserialize)]
-struct WebLoginCredentials {
-    refresh_token: String,
-    device_name: String,
+pub(crate) struct WebLoginCredentials {
+    pub(crate) refresh_token: String,
+    pub(crate) device_name: String,
+}
+
+/// A way for the user to sign the device in to their account
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub(crate) trait SignInFlow: Send + Sync {
+    /// Waits until the user has signed in
+    async fn sign_in(&self) -> Result<WebLoginCredentials, Box<dyn Error + Send + Sync>>;
+}
+
+/// The login page posts the credentials together with the nonce it was served with
+#[derive(Deserialize)]
+struct LoginRequest {
+    nonce: String,
+    #[serde(flatten)]
+    credentials: WebLoginCredentials,
 }
 
-struct ChannelState {
-    tx: Arc<Mutex<Sender<WebLoginCredentials>>>,
+struct LoginState {
+    tx: Mutex<Sender<WebLoginCredentials>>,
+    /// Accepted with the credentials once, then discarded. Only given out in the URL opened in
+    /// the user's browser, never by the server itself.
+    nonce: Mutex<Option<String>>,
+}
+
+impl LoginState {
+    fn new(tx: Sender<WebLoginCredentials>, nonce: String) -> Self {
+        Self {
+            tx: Mutex::new(tx),
+            nonce: Mutex::new(Some(nonce)),
+        }
+    }
+}
+
+/// Only lets through requests addressed to the loopback interface, so that a web page can't
+/// reach the login server by rebinding its own domain name to 127.0.0.1
+struct LoopbackHost;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LoopbackHost {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let host = request.headers().get_one("Host");
+        match host.map(|host| host.rsplit_once(':').map_or(host, |(domain, _)| domain)) {
+            Some("127.0.0.1" | "localhost") => request::Outcome::Success(LoopbackHost),
+            _ => request::Outcome::Error((Status::Forbidden, ())),
+        }
+    }
 }
 
 use rust_embed::RustEmbed;
@@ -36,27 +91,38 @@ use rust_embed::RustEmbed;
 #[folder = "src/auth/static/"]
 struct Images;
 
-#[post("/login", format = "json", data = "<user_credentials>")]
-fn login(user_credentials: Json<WebLoginCredentials>, tx: &State<ChannelState>) -> String {
-    info!("IN LOGIN");
-    info!("User credentials: {:?}", user_credentials);
-    // let tmp = tx.tx.lock().unwrap().se;
-    tx.tx
+#[post("/login", format = "json", data = "<request>")]
+fn login(
+    _host: LoopbackHost,
+    request: Json<LoginRequest>,
+    state: &State<LoginState>,
+) -> Result<&'static str, Status> {
+    let request = request.into_inner();
+    {
+        let mut nonce = state.nonce.lock().unwrap();
+        if nonce.as_deref() != Some(request.nonce.as_str()) {
+            warn!("Rejected a login which didn't come from the login page");
+            return Err(Status::Forbidden);
+        }
+        *nonce = None;
+    }
+
+    info!(
+        "Received credentials for device {:?}",
+        request.credentials.device_name
+    );
+    state
+        .tx
         .lock()
         .unwrap()
-        .send(WebLoginCredentials {
-            refresh_token: user_credentials.refresh_token.clone(),
-            device_name: user_credentials.device_name.clone(),
-        })
-        .expect("Unable to send on channel");
-    info!("attempted to send on channel");
-
-    format!("Welcome, {}!", user_credentials.refresh_token)
+        .send(request.credentials)
+        .map_err(|_| Status::ServiceUnavailable)?;
+    Ok("Logged in")
 }
 
 #[get("/")]
-async fn index() -> RawHtml<String> {
-    RawHtml(String::from(include_str!("index.html")))
+fn index(_host: LoopbackHost) -> RawHtml<&'static str> {
+    RawHtml(include_str!("index.html"))
 }
 
 #[get("/static/<file..>")]
@@ -72,11 +138,115 @@ fn image(file: PathBuf) -> Option<(ContentType, Cow<'static, [u8]>)> {
     Some((content_type, asset.data))
 }
 
-fn rocket() -> Rocket<Build> {
-    rocket::build().mount("/", routes![index, login, image])
+/// The login server, listening on the loopback interface only, on a port picked when it binds
+fn rocket(state: LoginState) -> Rocket<Build> {
+    let config = rocket::Config {
+        address: Ipv4Addr::LOCALHOST.into(),
+        port: 0,
+        log_level: LogLevel::Critical,
+        shutdown: Shutdown {
+            ctrlc: false,
+            ..Default::default()
+        },
+        ..rocket::Config::release_default()
+    };
+    rocket::custom(config)
+        .manage(state)
+        .mount("/", routes![index, login, image])
+}
+
+/// A random value which can't be guessed by other processes or web pages
+fn login_nonce() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+/// Starts the login server, returning the port it bound. Binding to port 0 lets the OS pick a
+/// free port atomically, so nothing else can take it before the server starts listening.
+async fn launch_login_server(
+    state: LoginState,
+) -> Result<(u16, rocket::Shutdown), Box<dyn Error + Send + Sync>> {
+    let (port_tx, port_rx) = oneshot::channel();
+    let rocket = rocket(state)
+        .attach(AdHoc::on_liftoff("Bound port", |rocket| {
+            Box::pin(async move {
+                let _ = port_tx.send(rocket.config().port);
+            })
+        }))
+        .ignite()
+        .await?;
+    let shutdown_handle = rocket.shutdown();
+
+    rocket::tokio::spawn(rocket.launch());
+    Ok((port_rx.await?, shutdown_handle))
+}
+
+/// The login page's address. The nonce goes in the fragment, which the browser keeps to itself,
+/// so it never reaches the server or any log.
+fn login_url(port: u16, nonce: &str) -> String {
+    format!("http://127.0.0.1:{}/#nonce={}", port, nonce)
+}
+
+/// Signs in through a web page served locally and opened in the user's browser
+pub(crate) struct WebpageSignIn;
+
+#[async_trait]
+impl SignInFlow for WebpageSignIn {
+    /// Launches a local server and opens the web page for user to type in username and password.
+    ///
+    /// Errors if any of those processes fail.
+    async fn sign_in(&self) -> Result<WebLoginCredentials, Box<dyn Error + Send + Sync>> {
+        let (tx, rx) = channel::<WebLoginCredentials>();
+        let nonce = login_nonce();
+        let (port, shutdown_handle) =
+            launch_login_server(LoginState::new(tx, nonce.clone())).await?;
+
+        Command::new("xdg-open")
+            .arg(login_url(port, &nonce))
+            .spawn()?;
+
+        // Wait until the user logs in on web browser
+        let web_login_credentials = tokio::task::spawn_blocking(move || rx.recv()).await??;
+
+        shutdown_handle.notify();
+
+        Ok(web_login_credentials)
+    }
+}
+
+/// Version of the device file format written by this build. Older files are migrated forward
+/// by [`MIGRATIONS`] when they are loaded.
+const DEVICE_FILE_VERSION: u64 = 1;
+
+/// `MIGRATIONS[n]` upgrades a device file from version `n` to version `n + 1`
+const MIGRATIONS: [fn(&mut Map<String, Value>); DEVICE_FILE_VERSION as usize] = [migrate_v0];
+
+/// Version 0 files carry no version, and were written before devices were named
+fn migrate_v0(file: &mut Map<String, Value>) {
+    file.entry("name").or_insert_with(|| Value::from(""));
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum DeviceFileError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The file can't be read back, e.g. after a truncated write or if it was edited
+    #[error("The device file is corrupt: {0}")]
+    Corrupt(String),
+
+    #[error("The device file has version {0}, which is newer than this program supports")]
+    NewerVersion(u64),
 }
 
-#[derive(Serialize, Debug, Deserialize, Clone)]
+/// The device file as stored, tagged with its format version
+#[derive(Serialize)]
+struct DeviceFile<'a> {
+    version: u64,
+    #[serde(flatten)]
+    device: &'a Device,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq)]
 pub struct Device {
     pub refresh_token: Token,
     pub id_token: Token,
@@ -86,151 +256,133 @@ pub struct Device {
 }
 
 impl Device {
-    pub async fn new(fire_auth: &FireAuth, client: &Client) -> Result<Device, Box<dyn Error>> {
-        let mut device = Device {
-            refresh_token: String::new(),
-            id_token: String::new(),
-            uuid: String::new(),
-            name: String::new(),
-            safe_shutdown_id: "".to_string(),
+    /// Loads the device info from the local file, migrating it from older formats. The stored id
+    /// token has usually expired, so it should be refreshed before use.
+    ///
+    /// Device files written before they were encrypted are still read, and are encrypted the
+    /// next time they are written.
+    pub(crate) fn from_file(path: &Path) -> Result<Device, DeviceFileError> {
+        let contents = fs::read(path)?;
+        let plaintext = if storage::is_encrypted(&contents) {
+            storage::decrypt(path, &contents).map_err(|e| match e {
+                StorageError::Io(e) => DeviceFileError::Io(e),
+                e => DeviceFileError::Corrupt(e.to_string()),
+            })?
+        } else {
+            warn!("Device file {:?} is not encrypted", path);
+            contents
         };
 
-        device.webpage_sign_in().await?;
-        device.id_token = refresh_id_token(device.refresh_token.clone(), fire_auth).await?;
-        match device.register_device(fire_auth, client).await {
-            Ok(res) if res.status() == StatusCode::OK => {
-                info!("Successfully registered device");
-                device.uuid = res.text().await?;
-            }
-            a => {
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Failed to register device: {:?}", a),
-                )));
-            }
+        let corrupt = |e: serde_json::Error| DeviceFileError::Corrupt(e.to_string());
+        let mut file: Map<String, Value> = serde_json::from_slice(&plaintext).map_err(corrupt)?;
+        let version = match file.remove("version") {
+            None => 0,
+            Some(version) => version
+                .as_u64()
+                .ok_or_else(|| DeviceFileError::Corrupt(format!("invalid version {}", version)))?,
+        };
+        if version > DEVICE_FILE_VERSION {
+            return Err(DeviceFileError::NewerVersion(version));
         }
-
-        Ok(device)
+        for migrate in &MIGRATIONS[version as usize..] {
+            migrate(&mut file);
+        }
+        serde_json::from_value(Value::Object(file)).map_err(corrupt)
     }
 
-    /// Loads the device info from the local file and refreshes the id token.
-    pub async fn from_file(auth: &FireAuth) -> Result<Device, Box<dyn Error>> {
-        let contents = fs::read_to_string(DEVICE_INFO_PATH)?;
+    /// Stores the credentials from signing in. The id token has to be refreshed afterwards.
+    pub(crate) fn sign_in(&mut self, credentials: WebLoginCredentials) {
+        self.refresh_token = credentials.refresh_token;
+        self.name = credentials.device_name;
+        self.id_token = String::new();
+    }
 
-        let mut device: Device = serde_json::from_str(&contents)?;
-        device.id_token = refresh_id_token(device.refresh_token.clone(), auth).await?;
-        Ok(device)
+    /// Exchanges the refresh token for a new id token, retrying with backoff
+    pub async fn refresh_id_token(&mut self, api: &dyn AccountabilityApi) -> Result<(), AuthError> {
+        self.id_token = refresh_with_backoff(|| api.refresh_id_token(&self.refresh_token)).await?;
+        Ok(())
     }
 
-    /// Launches a local server and opens the web page for user to type in username and password.
-    ///
-    /// Errors if any of those processes fail.
-    async fn webpage_sign_in(&mut self) -> Result<(), Box<dyn Error>> {
-        Command::new("xdg-open")
-            .arg("http://localhost:8000")
-            .spawn()?;
+    /// The claims of the id token, which is a JWT
+    fn id_token_claims<T: DeserializeOwned>(&self) -> Option<T> {
+        let payload = self.id_token.split('.').nth(1)?;
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+    }
 
-        let (tx, rx) = channel::<WebLoginCredentials>();
-        let rocket = rocket()
-            .manage(ChannelState {
-                tx: Arc::new(Mutex::new(tx)),
-            })
-            .ignite()
-            .await?;
-        let shutdown_handle = rocket.shutdown();
+    /// When the id token expires, from its JWT `exp` claim
+    pub(crate) fn id_token_expiry(&self) -> Option<SystemTime> {
+        #[derive(Deserialize)]
+        struct Claims {
+            exp: u64,
+        }
 
-        rocket::tokio::spawn(rocket.launch());
+        let claims: Claims = self.id_token_claims()?;
+        Some(UNIX_EPOCH + Duration::from_secs(claims.exp))
+    }
 
-        // Block until the user logs in on web browser
-        let web_login_credentials = rx.recv()?;
-        self.refresh_token = web_login_credentials.refresh_token;
-        self.name = web_login_credentials.device_name;
+    /// The account the device is signed in to, from the id token's JWT `sub` claim
+    pub(crate) fn account_id(&self) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Claims {
+            sub: String,
+        }
 
-        shutdown_handle.notify();
+        self.id_token_claims::<Claims>().map(|claims| claims.sub)
+    }
 
-        Ok(())
+    /// Whether the id token expires within `margin`. Tokens without an expiry are only refreshed
+    /// once the server rejects them.
+    pub(crate) fn id_token_expires_within(&self, margin: Duration) -> bool {
+        self.id_token_expiry()
+            .is_some_and(|expiry| expiry <= SystemTime::now() + margin)
     }
 
+    /// Registers the device with the server and stores the new device uuid.
     pub async fn register_device(
         &mut self,
-        fire_auth: &FireAuth,
-        client: &Client,
-    ) -> Result<Response, Box<dyn Error>> {
-        let mut request_json = DeviceRegisterJson {
-            id_token: self.id_token.clone(),
-            device_name: self.name.clone(),
-        };
-        let request_builder = client
-            .post("https://us-central1-openaccountability.cloudfunctions.net/api/device")
-            .bearer_auth(self.refresh_token.as_str())
-            .json(&request_json);
-        let res =
-            make_request_with_id_token(fire_auth, self, request_builder, &mut request_json).await?;
-
-        Ok(res)
+        api: &dyn AccountabilityApi,
+    ) -> Result<(), Box<dyn Error>> {
+        self.uuid = api.register_device(self).await?;
+        info!("Successfully registered device");
+        Ok(())
     }
 
-    pub(crate) fn write_device_info(&self, with_safe_exit_id: bool) -> Result<(), Box<dyn Error>> {
-        let serialized = if with_safe_exit_id {
-            serde_json::to_string(&self)?
-        } else {
-            let mut device_tmp = self.clone();
-            device_tmp.safe_shutdown_id = "".to_string();
-            serde_json::to_string(&device_tmp)?
-        };
+    pub(crate) fn write_device_info(
+        &self,
+        path: &Path,
+        with_safe_exit_id: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut device = Cow::Borrowed(self);
+        if !with_safe_exit_id {
+            device.to_mut().safe_shutdown_id = "".to_string();
+        }
+        let serialized = serde_json::to_vec(&DeviceFile {
+            version: DEVICE_FILE_VERSION,
+            device: &device,
+        })?;
 
-        // Write the serialized data to a file
-        fs::write(DEVICE_INFO_PATH, serialized)?;
+        storage::write_encrypted(path, &serialized)?;
         Ok(())
     }
 
     pub async fn get_safe_exit_id(
         &mut self,
-        fire_auth: &FireAuth,
-        client: &Client,
+        api: &dyn AccountabilityApi,
     ) -> Result<(), Box<dyn Error>> {
-        let mut request_json = GetSafeExitIdJson {
-            id_token: self.id_token.clone(),
-            device_uuid: self.uuid.clone(),
-        };
-        let request_builder = client
-            .post(
-                "https://us-central1-openaccountability.cloudfunctions.net/api/device/safe_exit_id",
-            )
-            .bearer_auth(self.refresh_token.as_str())
-            .json(&request_json);
-        let res =
-            make_request_with_id_token(fire_auth, self, request_builder, &mut request_json).await?;
-
-        self.safe_shutdown_id = res.text().await?;
+        self.safe_shutdown_id = api.get_safe_exit_id(self).await?;
         Ok(())
     }
 
     pub async fn check_safe_exit_id(
         &mut self,
-        fire_auth: &FireAuth,
-        client: &Client,
+        api: &dyn AccountabilityApi,
     ) -> Result<(), Box<dyn Error>> {
         info!("Checking safe exit id");
 
-        let mut request_json = CheckSafeExitIdJson {
-            id_token: self.id_token.clone(),
-            device_uuid: self.uuid.clone(),
-            safe_exit_id: self.safe_shutdown_id.clone(),
-        };
-        let request_builder = client
-            .patch(
-                "https://us-central1-openaccountability.cloudfunctions.net/api/device/safe_exit_id",
-            )
-            .bearer_auth(self.refresh_token.as_str())
-            .json(&request_json);
-        let res =
-            make_request_with_id_token(fire_auth, self, request_builder, &mut request_json).await?;
-        if res.status().is_success() {
+        if api.check_safe_exit_id(self).await? {
             Ok(())
         } else {
-            Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            Err(Box::new(std::io::Error::other(
                 "Safe exit id does not match",
             )))
         }
@@ -241,23 +393,136 @@ impl Device {
 mod tests {
     use super::*;
 
+    use crate::auth::{test_jwt, DEVICE_FILE_LOCK, TEST_DEVICE_INFO_PATH};
+    use crate::requests::api::MockAccountabilityApi;
+    use crate::requests::refresh_id_token;
+    use crate::test_util::{temp_dir, test_device};
     use pretty_assertions::assert_eq;
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use std::sync::mpsc::Receiver;
 
-    fn create_device_info_file_from_env() {
+    const TEST_REFRESH_TOKEN: &str = "test_refresh_token";
+    const TEST_DEVICE_UID: &str = "test_device_uid";
+
+    fn create_device_info_file() {
         let device = Device {
-            refresh_token: dotenv!("TEST_REFRESH_TOKEN").to_string(),
             id_token: "".to_string(),
-            uuid: dotenv!("TEST_DEVICE_UID").to_string(),
-            name: "".to_string(),
-            safe_shutdown_id: "".to_string(),
+            ..test_device()
+        };
+        device
+            .write_device_info(Path::new(TEST_DEVICE_INFO_PATH), false)
+            .unwrap();
+    }
+
+    /// A mock server which hands out id tokens for the test refresh token
+    fn mock_api() -> MockAccountabilityApi {
+        let mut api = MockAccountabilityApi::new();
+        api.expect_refresh_id_token()
+            .withf(|refresh_token| refresh_token == TEST_REFRESH_TOKEN)
+            .returning(|_| Ok("test_id_token".to_string()));
+        api
+    }
+
+    async fn login_client() -> (Client, Receiver<WebLoginCredentials>) {
+        let (tx, rx) = channel();
+        let state = LoginState::new(tx, "testnonce".to_string());
+        (Client::untracked(rocket(state)).await.unwrap(), rx)
+    }
+
+    fn login_body(nonce: &str) -> String {
+        format!(
+            r#"{{"nonce":"{}","refresh_token":"{}","device_name":"laptop"}}"#,
+            nonce, TEST_REFRESH_TOKEN
+        )
+    }
+
+    /// Anything on the machine can fetch the login page, so it must not carry the nonce
+    #[tokio::test]
+    async fn test_login_page_has_no_nonce() {
+        let (client, _rx) = login_client().await;
+
+        let page = client
+            .get("/")
+            .header(Header::new("Host", "127.0.0.1:8000"))
+            .dispatch()
+            .await;
+        assert_eq!(page.status(), Status::Ok);
+        assert!(!page.into_string().await.unwrap().contains("testnonce"));
+        assert_eq!(
+            login_url(8000, "testnonce"),
+            "http://127.0.0.1:8000/#nonce=testnonce"
+        );
+    }
+
+    /// The server reports the port the OS gave it, and serves on it
+    #[tokio::test]
+    async fn test_login_server_port() {
+        let (tx, _rx) = channel();
+        let (port, shutdown) = launch_login_server(LoginState::new(tx, "testnonce".to_string()))
+            .await
+            .unwrap();
+        assert_ne!(port, 0);
+
+        let page = reqwest::get(format!("http://127.0.0.1:{}/", port))
+            .await
+            .unwrap();
+        assert_eq!(page.status(), reqwest::StatusCode::OK);
+        shutdown.notify();
+    }
+
+    #[tokio::test]
+    async fn test_login_requires_nonce() {
+        let (client, rx) = login_client().await;
+        let post = |nonce: &'static str, host: &'static str| {
+            client
+                .post("/login")
+                .header(ContentType::JSON)
+                .header(Header::new("Host", host))
+                .body(login_body(nonce))
         };
-        device.write_device_info(false).unwrap();
+
+        assert_eq!(
+            post("guess", "127.0.0.1:8000").dispatch().await.status(),
+            Status::Forbidden
+        );
+        // A page which rebinds its own domain to the loopback interface is turned away
+        assert_eq!(
+            post("testnonce", "attacker.example:8000")
+                .dispatch()
+                .await
+                .status(),
+            Status::Forbidden
+        );
+        assert!(rx.try_recv().is_err());
+
+        let response = post("testnonce", "localhost:8000").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        // The refresh token is not echoed back
+        assert!(!response
+            .into_string()
+            .await
+            .unwrap()
+            .contains(TEST_REFRESH_TOKEN));
+        let credentials = rx.try_recv().unwrap();
+        assert_eq!(credentials.refresh_token, TEST_REFRESH_TOKEN);
+        assert_eq!(credentials.device_name, "laptop");
+
+        // The nonce only works once
+        assert_eq!(
+            post("testnonce", "127.0.0.1:8000")
+                .dispatch()
+                .await
+                .status(),
+            Status::Forbidden
+        );
     }
 
     #[tokio::test]
+    #[ignore = "requires live Firebase credentials"]
     async fn test_refresh_id_token() {
-        let api_key: String = dotenv!("API_KEY").to_string();
-        let refresh_token: String = dotenv!("TEST_REFRESH_TOKEN").to_string();
+        let api_key = std::env::var("API_KEY").unwrap();
+        let refresh_token = std::env::var("TEST_REFRESH_TOKEN").unwrap();
         let auth = fireauth::FireAuth::new(api_key);
 
         let id_token = refresh_id_token(refresh_token, &auth).await.unwrap();
@@ -265,75 +530,162 @@ mod tests {
         assert_ne!(id_token, ""); // TODO: Submit a PR to rust lang to add an error message to suggest assert_ne if user types assert_neq
     }
 
+    #[test]
+    fn test_id_token_expiry() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut device = Device {
+            id_token: test_jwt(now + 60 * 60),
+            ..test_device()
+        };
+        assert_eq!(
+            device.id_token_expiry(),
+            Some(UNIX_EPOCH + Duration::from_secs(now + 60 * 60))
+        );
+        assert!(!device.id_token_expires_within(Duration::from_secs(5 * 60)));
+        assert!(device.id_token_expires_within(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(device.account_id().unwrap(), "test_account");
+
+        // A token which isn't a JWT is only refreshed when the server rejects it
+        device.id_token = "test_id_token".to_string();
+        assert_eq!(device.id_token_expiry(), None);
+        assert_eq!(device.account_id(), None);
+        assert!(!device.id_token_expires_within(Duration::from_secs(5 * 60)));
+    }
+
     #[tokio::test]
     async fn test_from_file() {
-        create_device_info_file_from_env();
+        let _lock = DEVICE_FILE_LOCK.lock().await;
+        create_device_info_file();
 
-        let api_key: String = dotenv!("API_KEY").to_string();
-        let auth = fireauth::FireAuth::new(api_key);
+        let api = mock_api();
 
-        let device = Device::from_file(&auth).await.unwrap();
-        assert_ne!(device.id_token, "");
-        assert_eq!(device.uuid, dotenv!("TEST_DEVICE_UID").to_string());
-        assert_eq!(
-            device.refresh_token,
-            dotenv!("TEST_REFRESH_TOKEN").to_string()
-        );
+        let mut device = Device::from_file(Path::new(TEST_DEVICE_INFO_PATH)).unwrap();
+        assert_eq!(device.id_token, "");
+        device.refresh_id_token(&api).await.unwrap();
+        assert_eq!(device.id_token, "test_id_token");
+        assert_eq!(device.uuid, TEST_DEVICE_UID.to_string());
+        assert_eq!(device.refresh_token, TEST_REFRESH_TOKEN.to_string());
+    }
+
+    /// Device files from before encryption are still read
+    #[test]
+    fn test_from_plain_file() {
+        let dir = temp_dir();
+        let path = dir.path().join("device");
+        fs::write(
+            &path,
+            r#"{"refresh_token":"test_refresh_token","id_token":"","uuid":"test_device_uid","name":"","safe_shutdown_id":""}"#,
+        )
+        .unwrap();
+
+        let device = Device::from_file(&path).unwrap();
+        assert_eq!(device.uuid, TEST_DEVICE_UID);
+        assert_eq!(device.refresh_token, TEST_REFRESH_TOKEN);
+    }
+
+    /// Unversioned files from before devices were named are migrated, and rewritten with the
+    /// current version
+    #[test]
+    fn test_from_file_migrates() {
+        let dir = temp_dir();
+        let path = dir.path().join("device");
+        fs::write(
+            &path,
+            r#"{"refresh_token":"test_refresh_token","id_token":"","uuid":"test_device_uid","safe_shutdown_id":""}"#,
+        )
+        .unwrap();
+
+        let device = Device::from_file(&path).unwrap();
+        assert_eq!(device.uuid, TEST_DEVICE_UID);
+        assert_eq!(device.name, "");
+
+        device.write_device_info(&path, true).unwrap();
+        let plaintext = storage::decrypt(&path, &fs::read(&path).unwrap()).unwrap();
+        let file: Value = serde_json::from_slice(&plaintext).unwrap();
+        assert_eq!(file["version"], DEVICE_FILE_VERSION);
+        assert_eq!(Device::from_file(&path).unwrap().uuid, TEST_DEVICE_UID);
+    }
+
+    #[test]
+    fn test_from_file_rejects_bad_files() {
+        let dir = temp_dir();
+        let path = dir.path().join("device");
+
+        fs::write(&path, r#"{"version":99,"refresh_token":""}"#).unwrap();
+        assert!(matches!(
+            Device::from_file(&path),
+            Err(DeviceFileError::NewerVersion(99))
+        ));
+
+        fs::write(&path, r#"{"refresh_token":"test_refr"#).unwrap();
+        assert!(matches!(
+            Device::from_file(&path),
+            Err(DeviceFileError::Corrupt(_))
+        ));
     }
 
     #[tokio::test]
     async fn test_register_device() {
-        let client = Client::new();
-        create_device_info_file_from_env();
+        let _lock = DEVICE_FILE_LOCK.lock().await;
+        create_device_info_file();
+
+        let mut api = mock_api();
+        api.expect_register_device()
+            .times(1)
+            .returning(|_| Ok("new_device_uid".to_string()));
+
+        let mut device = Device::from_file(Path::new(TEST_DEVICE_INFO_PATH)).unwrap();
+        device.register_device(&api).await.unwrap();
+        assert_ne!(device.uuid, TEST_DEVICE_UID);
+        assert_eq!(device.uuid, "new_device_uid");
+    }
 
-        let api_key: String = dotenv!("API_KEY").to_string();
-        let auth = fireauth::FireAuth::new(api_key);
+    #[tokio::test]
+    async fn test_register_device_failure() {
+        let _lock = DEVICE_FILE_LOCK.lock().await;
+        create_device_info_file();
+
+        let mut api = mock_api();
+        api.expect_register_device()
+            .returning(|_| Err("Failed to register device".into()));
 
-        let mut device = Device::from_file(&auth).await.unwrap();
-        let res = device.register_device(&auth, &client).await.unwrap();
-        assert_eq!(res.status(), StatusCode::OK);
-        let uuid = res.text().await.unwrap();
-        assert_ne!(uuid, device.uuid);
-        assert_ne!(uuid, "");
+        let mut device = Device::from_file(Path::new(TEST_DEVICE_INFO_PATH)).unwrap();
+        assert!(device.register_device(&api).await.is_err());
+        assert_eq!(device.uuid, TEST_DEVICE_UID);
     }
 
     #[tokio::test]
     async fn test_get_safe_exit_id() {
-        let client = Client::new();
-        create_device_info_file_from_env();
+        let _lock = DEVICE_FILE_LOCK.lock().await;
+        create_device_info_file();
 
-        let api_key: String = dotenv!("API_KEY").to_string();
-        let auth = fireauth::FireAuth::new(api_key);
-
-        let mut device = Device::from_file(&auth).await.unwrap();
-        let res = device.register_device(&auth, &client).await.unwrap();
-        assert_eq!(res.status(), StatusCode::OK);
-        let uuid = res.text().await.unwrap();
-        assert_ne!(uuid.clone(), "");
-        device.uuid = uuid;
+        let mut api = mock_api();
+        api.expect_get_safe_exit_id()
+            .returning(|_| Ok("testexitid123".to_string()));
+        api.expect_check_safe_exit_id()
+            .withf(|device| device.safe_shutdown_id == "testexitid123")
+            .returning(|_| Ok(true));
 
-        device.get_safe_exit_id(&auth, &client).await.unwrap();
+        let mut device = Device::from_file(Path::new(TEST_DEVICE_INFO_PATH)).unwrap();
+        device.get_safe_exit_id(&api).await.unwrap();
 
         assert_eq!(device.safe_shutdown_id, "testexitid123".to_string());
 
-        device.check_safe_exit_id(&auth, &client).await.unwrap();
+        device.check_safe_exit_id(&api).await.unwrap();
     }
 
     #[tokio::test]
     async fn test_check_safe_exit_id() {
-        let client = Client::new();
-        create_device_info_file_from_env();
-
-        let api_key: String = dotenv!("API_KEY").to_string();
-        let auth = fireauth::FireAuth::new(api_key);
+        let _lock = DEVICE_FILE_LOCK.lock().await;
+        create_device_info_file();
 
-        let mut device = Device::from_file(&auth).await.unwrap();
-        let res = device.register_device(&auth, &client).await.unwrap();
-        assert_eq!(res.status(), StatusCode::OK);
-        let uuid = res.text().await.unwrap();
-        assert_ne!(uuid.clone(), "");
-        device.uuid = uuid;
+        let mut api = mock_api();
+        api.expect_check_safe_exit_id().returning(|_| Ok(false));
 
-        assert!(device.check_safe_exit_id(&auth, &client).await.is_err());
+        let mut device = Device::from_file(Path::new(TEST_DEVICE_INFO_PATH)).unwrap();
+        assert!(device.check_safe_exit_id(&api).await.is_err());
     }
 }
@@ -0,0 +1,204 @@
+//! Encrypted storage for the device's credentials.
+//!
+//! The device file is encrypted with ChaCha20-Poly1305 under a key derived from the machine id
+//! and a random secret kept next to it, both of which only the owner can read. A device file
+//! copied to another machine can't be decrypted there, so it can't be used to impersonate the
+//! device.
+
+use crate::tamper::watcher::expect_own_rename;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder, File, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Starts every encrypted file, so they can be told apart from the old plain JSON files
+const MAGIC: &[u8] = b"OADEV1";
+
+const NONCE_LENGTH: usize = 12;
+const SECRET_LENGTH: usize = 32;
+
+/// Separates this key from any other derived from the machine id
+const KEY_CONTEXT: &[u8] = b"open-accountability device credentials";
+
+const MACHINE_ID_PATHS: [&str; 2] = ["/etc/machine-id", "/var/lib/dbus/machine-id"];
+
+#[derive(Error, Debug)]
+pub(crate) enum StorageError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("No machine id found in {MACHINE_ID_PATHS:?}")]
+    NoMachineId,
+
+    #[error("The device file was encrypted on another machine, or has been modified")]
+    Decrypt,
+
+    #[error("Failed to encrypt the device file")]
+    Encrypt,
+}
+
+/// The secret mixed into the key of the file at `path`
+fn secret_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".key");
+    path.with_file_name(name)
+}
+
+fn machine_id() -> Result<String, StorageError> {
+    MACHINE_ID_PATHS
+        .iter()
+        .find_map(|path| {
+            fs::read_to_string(path)
+                .ok()
+                .map(|id| id.trim().to_string())
+                .filter(|id| !id.is_empty())
+        })
+        .ok_or(StorageError::NoMachineId)
+}
+
+/// Reads the secret for the file at `path`, creating it if it doesn't exist yet
+fn secret(path: &Path) -> Result<Vec<u8>, StorageError> {
+    let secret_path = secret_path(path);
+    match fs::read(&secret_path) {
+        Ok(secret) => Ok(secret),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let secret = rand::random::<[u8; SECRET_LENGTH]>().to_vec();
+            write_private(&secret_path, &secret)?;
+            Ok(secret)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn cipher(path: &Path) -> Result<ChaCha20Poly1305, StorageError> {
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_CONTEXT);
+    hasher.update(machine_id()?.as_bytes());
+    hasher.update(secret(path)?);
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&hasher.finalize())))
+}
+
+/// Writes `contents` so that only the owner can read it, creating the parent directory (also
+/// only accessible by the owner) if needed.
+///
+/// The contents are written to a temporary file which then replaces `path`, so a crash or power
+/// loss part way through leaves either the old file or the new one, never a truncated one.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+    if let Some(dir) = dir {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    let tmp_path = pending_path(path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    // The mode only applies to new files
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    expect_own_rename(path);
+    fs::rename(&tmp_path, path)?;
+    // Make the rename itself durable
+    File::open(dir.unwrap_or(Path::new(".")))?.sync_all()
+}
+
+/// Where the new contents of `path` are written before replacing it
+fn pending_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Whether `contents` were written by [`write_encrypted`], rather than being an old plain file
+pub(crate) fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+pub(crate) fn write_encrypted(path: &Path, plaintext: &[u8]) -> Result<(), StorageError> {
+    let nonce = rand::random::<[u8; NONCE_LENGTH]>();
+    let ciphertext = cipher(path)?
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| StorageError::Encrypt)?;
+    write_private(path, &[MAGIC, &nonce, &ciphertext].concat())?;
+    Ok(())
+}
+
+/// Decrypts the contents of the file at `path`, as read by the caller
+pub(crate) fn decrypt(path: &Path, contents: &[u8]) -> Result<Vec<u8>, StorageError> {
+    let sealed = contents
+        .strip_prefix(MAGIC)
+        .filter(|sealed| sealed.len() >= NONCE_LENGTH)
+        .ok_or(StorageError::Decrypt)?;
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    cipher(path)?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| StorageError::Decrypt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_dir;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    /// A device file in a state directory which doesn't exist yet
+    fn device_path(dir: &TempDir) -> PathBuf {
+        dir.path().join("state").join("device")
+    }
+
+    #[test]
+    fn test_round_trip_is_private() {
+        let dir = temp_dir();
+        let path = device_path(&dir);
+        write_encrypted(&path, b"{\"refresh_token\":\"secret\"}").unwrap();
+
+        let contents = fs::read(&path).unwrap();
+        assert!(is_encrypted(&contents));
+        assert!(!String::from_utf8_lossy(&contents).contains("secret"));
+        assert_eq!(
+            decrypt(&path, &contents).unwrap(),
+            b"{\"refresh_token\":\"secret\"}"
+        );
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(&secret_path(&path)), 0o600);
+        assert_eq!(mode(path.parent().unwrap()), 0o700);
+        assert!(!pending_path(&path).exists());
+    }
+
+    /// A device file copied without its secret, or onto another machine, can't be read
+    #[test]
+    fn test_copied_file_is_unreadable() {
+        let (dir, copy_dir) = (temp_dir(), temp_dir());
+        let path = device_path(&dir);
+        write_encrypted(&path, b"credentials").unwrap();
+        let contents = fs::read(&path).unwrap();
+
+        let copy = device_path(&copy_dir);
+        fs::create_dir_all(copy.parent().unwrap()).unwrap();
+        fs::write(&copy, &contents).unwrap();
+        assert!(matches!(
+            decrypt(&copy, &contents),
+            Err(StorageError::Decrypt)
+        ));
+
+        let mut tampered = contents.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decrypt(&path, &tampered),
+            Err(StorageError::Decrypt)
+        ));
+    }
+}
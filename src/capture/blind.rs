@@ -0,0 +1,280 @@
+//! Detection of captures which can't see the screen.
+//!
+//! A compositor which blocks capture usually hands back blank or stale frames instead of an
+//! error, and OCR finds no words in them. Such frames are classified here so that they are
+//! reported as blind captures rather than as clean capture cycles.
+
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+
+/// Channel values within this distance of the first pixel count as the same colour
+const UNIFORM_TOLERANCE: u8 = 8;
+
+/// Fraction of pixels which may differ from the first one in a uniform frame, so that a mouse
+/// cursor on a black screen doesn't hide it
+const UNIFORM_MAX_DIFFERENT: f64 = 0.001;
+
+/// A screen which returns the same frame this many times in a row, while the keyboard or mouse
+/// is in use, is frozen
+const FROZEN_FRAME_COUNT: usize = 3;
+
+/// Interrupt sources in `/proc/interrupts` which fire on keyboard, mouse or touchpad input
+const INPUT_INTERRUPTS: [&str; 2] = ["i8042", "hid"];
+
+/// Why a captured screen couldn't be seen
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BlindReason {
+    /// The capture source returned an error
+    CaptureFailed,
+    /// No frames, or a frame with no pixels
+    Empty,
+    /// The whole frame is a single colour while the user is active, or for the first time since
+    /// the screen last showed anything
+    Uniform,
+    /// The frame hasn't changed for several captures even though the user is active
+    Frozen,
+}
+
+impl BlindReason {
+    /// Whether the frame still shows something to read. A frozen frame may be stale, but
+    /// whatever was left on screen still counts.
+    pub(crate) fn is_readable(self) -> bool {
+        matches!(self, BlindReason::Frozen)
+    }
+}
+
+#[derive(Default)]
+struct ScreenHistory {
+    last_hash: Option<u64>,
+    repeats: usize,
+    /// Whether the last frame was uniform
+    uniform: bool,
+}
+
+/// Classifies each capture's frames, remembering earlier frames to spot frozen screens
+#[derive(Default)]
+pub(crate) struct BlindDetector {
+    screens: Vec<ScreenHistory>,
+}
+
+impl BlindDetector {
+    /// Returns the index and reason of every blind screen in a capture. `input_active` is
+    /// whether the user touched the keyboard or mouse since the previous capture.
+    pub(crate) fn classify(
+        &mut self,
+        frames: &[DynamicImage],
+        input_active: bool,
+    ) -> Vec<(usize, BlindReason)> {
+        if frames.is_empty() {
+            return vec![(0, BlindReason::Empty)];
+        }
+        self.screens
+            .resize_with(frames.len(), ScreenHistory::default);
+
+        let mut blind = Vec::new();
+        for (screen, (frame, history)) in frames.iter().zip(self.screens.iter_mut()).enumerate() {
+            if frame.width() == 0 || frame.height() == 0 {
+                blind.push((screen, BlindReason::Empty));
+                continue;
+            }
+
+            let hash = frame_hash(frame);
+            if history.last_hash == Some(hash) && input_active {
+                history.repeats += 1;
+            } else if history.last_hash != Some(hash) {
+                history.repeats = 0;
+            }
+            history.last_hash = Some(hash);
+
+            // An idle screen can really be blank, such as an empty desktop or a blanked lock
+            // screen, so it is only reported once until it shows something again
+            let was_uniform = history.uniform;
+            history.uniform = is_uniform(frame);
+            if history.uniform {
+                if input_active || !was_uniform {
+                    blind.push((screen, BlindReason::Uniform));
+                }
+            } else if history.repeats >= FROZEN_FRAME_COUNT {
+                blind.push((screen, BlindReason::Frozen));
+            }
+        }
+        blind
+    }
+}
+
+fn frame_hash(frame: &DynamicImage) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (frame.width(), frame.height()).hash(&mut hasher);
+    frame.as_bytes().hash(&mut hasher);
+    hasher.finish()
+}
+
+fn is_uniform(frame: &DynamicImage) -> bool {
+    let frame = frame.to_rgb8();
+    let first = frame.get_pixel(0, 0).0;
+    let different = frame
+        .pixels()
+        .filter(|pixel| {
+            pixel
+                .0
+                .iter()
+                .zip(first.iter())
+                .any(|(a, b)| a.abs_diff(*b) > UNIFORM_TOLERANCE)
+        })
+        .count();
+    (different as f64) <= UNIFORM_MAX_DIFFERENT * (frame.width() * frame.height()) as f64
+}
+
+/// Notices keyboard and mouse activity from the interrupt counts in `/proc/interrupts`.
+///
+/// Only input devices with their own interrupt line (PS/2 keyboards and most laptop touchpads)
+/// are seen. Input through a USB hub is not, so on such machines frozen screens go unreported
+/// rather than being reported falsely.
+pub(crate) struct InputActivity {
+    last_count: Option<u64>,
+}
+
+impl InputActivity {
+    pub(crate) fn new() -> Self {
+        let last_count = input_interrupt_count();
+        if last_count.is_none() {
+            warn!("No input interrupts found, frozen screens won't be detected");
+        }
+        Self { last_count }
+    }
+
+    /// Returns whether there was any input since the previous call
+    pub(crate) fn check(&mut self) -> bool {
+        let count = input_interrupt_count();
+        let active = matches!((self.last_count, count), (Some(last), Some(now)) if now > last);
+        self.last_count = count;
+        active
+    }
+}
+
+fn input_interrupt_count() -> Option<u64> {
+    parse_input_interrupts(&fs::read_to_string("/proc/interrupts").ok()?)
+}
+
+/// Sums the counts, over every CPU, of the interrupts raised by input devices
+fn parse_input_interrupts(interrupts: &str) -> Option<u64> {
+    let mut lines = interrupts.lines();
+    let cpus = lines.next()?.split_whitespace().count();
+    let mut total = None;
+    for line in lines {
+        let lowercase = line.to_lowercase();
+        if !INPUT_INTERRUPTS.iter().any(|name| lowercase.contains(name)) {
+            continue;
+        }
+        let count: u64 = line
+            .split_whitespace()
+            .skip(1)
+            .take(cpus)
+            .filter_map(|field| field.parse::<u64>().ok())
+            .sum();
+        *total.get_or_insert(0) += count;
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgb, RgbImage};
+    use pretty_assertions::assert_eq;
+
+    fn noise() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |_, _| Rgb(rand::random())))
+    }
+
+    #[test]
+    fn test_uniform_and_empty_frames() {
+        let mut black = RgbImage::new(200, 100);
+        // A mouse cursor doesn't make a black screen visible
+        black.put_pixel(10, 10, Rgb([255, 255, 255]));
+        let mut detector = BlindDetector::default();
+
+        assert_eq!(
+            detector.classify(
+                &[
+                    noise(),
+                    DynamicImage::ImageRgb8(black),
+                    DynamicImage::new_rgb8(0, 0)
+                ],
+                false
+            ),
+            vec![(1, BlindReason::Uniform), (2, BlindReason::Empty)]
+        );
+        assert_eq!(detector.classify(&[], false), vec![(0, BlindReason::Empty)]);
+    }
+
+    /// A blank screen nobody is using is reported once, not every cycle, but is reported every
+    /// time while the user is active
+    #[test]
+    fn test_idle_uniform_screen() {
+        let blank = vec![DynamicImage::new_rgb8(200, 100)];
+        let mut detector = BlindDetector::default();
+
+        assert_eq!(
+            detector.classify(&blank, false),
+            vec![(0, BlindReason::Uniform)]
+        );
+        for _ in 0..10 {
+            assert!(detector.classify(&blank, false).is_empty());
+        }
+        for _ in 0..2 {
+            assert_eq!(
+                detector.classify(&blank, true),
+                vec![(0, BlindReason::Uniform)]
+            );
+        }
+
+        // Blanking again after showing something is a new streak
+        assert!(detector.classify(&[noise()], false).is_empty());
+        assert_eq!(
+            detector.classify(&blank, false),
+            vec![(0, BlindReason::Uniform)]
+        );
+    }
+
+    #[test]
+    fn test_frozen_only_while_input_active() {
+        let frames = vec![noise()];
+        let mut detector = BlindDetector::default();
+
+        // A static screen nobody is using is not frozen
+        for _ in 0..10 {
+            assert!(detector.classify(&frames, false).is_empty());
+        }
+        for _ in 0..FROZEN_FRAME_COUNT - 1 {
+            assert!(detector.classify(&frames, true).is_empty());
+        }
+        assert_eq!(
+            detector.classify(&frames, true),
+            vec![(0, BlindReason::Frozen)]
+        );
+        // Whatever was left on a frozen screen is still read
+        assert!(BlindReason::Frozen.is_readable());
+        assert!(!BlindReason::Uniform.is_readable());
+
+        // A changed frame starts over
+        assert!(detector.classify(&[noise()], true).is_empty());
+    }
+
+    #[test]
+    fn test_parse_input_interrupts() {
+        let interrupts = "           CPU0       CPU1       \n\
+            \x20  1:         10          5  IR-IO-APIC    1-edge      i8042\n\
+            \x20  8:          0          0  IR-IO-APIC    8-edge      rtc0\n\
+            \x20 12:        100        200  IR-IO-APIC   12-edge      i8042\n\
+            \x20 51:          7          0  IR-IO-APIC   51-fasteoi   i2c_hid_acpi\n\
+            NMI:          0          0   Non-maskable interrupts\n";
+        assert_eq!(parse_input_interrupts(interrupts), Some(322));
+        assert_eq!(parse_input_interrupts("   CPU0\n  8:  0  rtc0\n"), None);
+    }
+}
@@ -0,0 +1,69 @@
+//! Capture sources which don't look at the screen, for running the daemon without a display.
+//!
+//! Only built into tests and builds with the `dev-capture` feature, since a daemon which can be
+//! told to replay harmless images isn't monitoring anything.
+
+use crate::capture::CaptureSource;
+use crate::ImageReader;
+use async_trait::async_trait;
+use image::{DynamicImage, Rgb, RgbImage};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Replays the PNG files in a directory, one per capture, in file name order. Starts over once
+/// every file has been returned.
+pub(crate) struct ReplayCapture {
+    frames: Vec<PathBuf>,
+    next: usize,
+}
+
+impl ReplayCapture {
+    pub(crate) fn new(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut frames = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+            })
+            .collect::<Vec<PathBuf>>();
+        if frames.is_empty() {
+            return Err(format!("No PNG files to replay in {:?}", dir).into());
+        }
+        frames.sort();
+        Ok(Self { frames, next: 0 })
+    }
+}
+
+#[async_trait]
+impl CaptureSource for ReplayCapture {
+    async fn capture(&mut self) -> Result<Vec<DynamicImage>, Box<dyn Error>> {
+        let path = &self.frames[self.next];
+        self.next = (self.next + 1) % self.frames.len();
+        info!("replaying {:?}", path);
+        Ok(vec![ImageReader::open(path)?
+            .with_guessed_format()?
+            .decode()?])
+    }
+}
+
+/// Generates frames of random noise, for exercising the pipeline when neither a display nor
+/// recorded images are available
+pub(crate) struct SyntheticCapture {
+    width: u32,
+    height: u32,
+}
+
+impl SyntheticCapture {
+    pub(crate) fn new(width: u32, height: u32) -> Self {
+        Self { width, height }
+    }
+}
+
+#[async_trait]
+impl CaptureSource for SyntheticCapture {
+    async fn capture(&mut self) -> Result<Vec<DynamicImage>, Box<dyn Error>> {
+        let img = RgbImage::from_fn(self.width, self.height, |_, _| Rgb(rand::random()));
+        Ok(vec![DynamicImage::ImageRgb8(img)])
+    }
+}
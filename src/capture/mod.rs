@@ -0,0 +1,141 @@
+//! Sources of screen images for the monitoring loop.
+//!
+//! The daemon captures the X11 screens by default, or goes through the xdg-desktop-portal on
+//! Wayland sessions. Builds with the `dev-capture` feature can replay a directory of PNGs
+//! instead, or generate synthetic frames, so that the whole pipeline can run without a display.
+
+mod blind;
+#[cfg(any(test, feature = "dev-capture"))]
+mod dev;
+mod portal;
+
+use crate::ImageReader;
+use async_trait::async_trait;
+use image::DynamicImage;
+use screenshots::Screen;
+use std::error::Error;
+use std::io::Cursor;
+use std::path::Path;
+
+pub(crate) use blind::{BlindDetector, BlindReason, InputActivity};
+#[cfg(any(test, feature = "dev-capture"))]
+pub(crate) use dev::{ReplayCapture, SyntheticCapture};
+pub(crate) use portal::PortalCapture;
+
+#[async_trait]
+pub(crate) trait CaptureSource: Send {
+    /// Captures one image per screen
+    async fn capture(&mut self) -> Result<Vec<DynamicImage>, Box<dyn Error>>;
+}
+
+/// Captures every X11 screen with the `screenshots` crate
+pub(crate) struct X11Capture;
+
+#[async_trait]
+impl CaptureSource for X11Capture {
+    async fn capture(&mut self) -> Result<Vec<DynamicImage>, Box<dyn Error>> {
+        let screens = Screen::all()?;
+        warn!("screens: {}", screens.len());
+
+        let mut images = Vec::with_capacity(screens.len());
+        for screen in screens {
+            let image = screen.capture()?;
+            let img = ImageReader::new(Cursor::new(image.buffer()))
+                .with_guessed_format()?
+                .decode()?;
+            images.push(img);
+        }
+        Ok(images)
+    }
+}
+
+/// Builds a capture source from its description:
+///
+/// - `x11`: capture the X11 screens
+/// - `portal`: capture through the xdg-desktop-portal screenshot interface
+/// - empty: `portal` on a Wayland session, otherwise `x11`
+/// - `replay:<directory>`: replay the PNG files in a directory (`dev-capture` builds only)
+/// - `synthetic` or `synthetic:<width>x<height>`: generate noise frames, 1920x1080 by default
+///   (`dev-capture` builds only)
+///
+/// `portal_permission_path` is where the portal source remembers that it may capture without
+/// asking.
+pub(crate) async fn from_spec(
+    spec: &str,
+    portal_permission_path: &Path,
+) -> Result<Box<dyn CaptureSource>, Box<dyn Error>> {
+    let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+    match (kind, arg) {
+        ("", "") if std::env::var_os("WAYLAND_DISPLAY").is_some() => {
+            Ok(Box::new(PortalCapture::new(portal_permission_path).await?))
+        }
+        ("" | "x11", "") => Ok(Box::new(X11Capture)),
+        ("portal", "") => Ok(Box::new(PortalCapture::new(portal_permission_path).await?)),
+        #[cfg(any(test, feature = "dev-capture"))]
+        ("replay", dir) if !dir.is_empty() => Ok(Box::new(ReplayCapture::new(Path::new(dir))?)),
+        #[cfg(any(test, feature = "dev-capture"))]
+        ("synthetic", "") => Ok(Box::new(SyntheticCapture::new(1920, 1080))),
+        #[cfg(any(test, feature = "dev-capture"))]
+        ("synthetic", arg) => {
+            let (width, height) = arg
+                .split_once('x')
+                .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                .ok_or_else(|| format!("Invalid synthetic capture size: {}", arg))?;
+            Ok(Box::new(SyntheticCapture::new(width, height)))
+        }
+        _ => Err(format!("Unknown capture source: {}", spec).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_replay_cycles_through_directory() {
+        let mut capture = from_spec("replay:tests", Path::new("")).await.unwrap();
+
+        let first = capture.capture().await.unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!((first[0].width(), first[0].height()), (133, 413));
+
+        let second = capture.capture().await.unwrap();
+        assert_eq!((second[0].width(), second[0].height()), (1920, 1080));
+
+        let third = capture.capture().await.unwrap();
+        assert_eq!((third[0].width(), third[0].height()), (133, 413));
+    }
+
+    #[tokio::test]
+    async fn test_replay_requires_images() {
+        assert!(from_spec("replay:src/auth", Path::new("")).await.is_err());
+        assert!(from_spec("replay:does/not/exist", Path::new(""))
+            .await
+            .is_err());
+        assert!(from_spec("replay", Path::new("")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_synthetic() {
+        let frames = from_spec("synthetic:64x32", Path::new(""))
+            .await
+            .unwrap()
+            .capture()
+            .await
+            .unwrap();
+        assert_eq!((frames[0].width(), frames[0].height()), (64, 32));
+
+        let frames = from_spec("synthetic", Path::new(""))
+            .await
+            .unwrap()
+            .capture()
+            .await
+            .unwrap();
+        assert_eq!((frames[0].width(), frames[0].height()), (1920, 1080));
+
+        assert!(from_spec("synthetic:64", Path::new("")).await.is_err());
+        assert!(from_spec("wayland", Path::new("")).await.is_err());
+    }
+}
@@ -0,0 +1,360 @@
+//! Screen capture through the xdg-desktop-portal Screenshot interface, for Wayland sessions
+//! where the X11 capture sees nothing.
+//!
+//! The first capture is interactive, so the user grants the daemon permission to take
+//! screenshots. The portal keeps that permission itself; the daemon only writes a marker file
+//! once the portal accepts an interactive request, so that every later capture (including after
+//! a restart) is requested without a dialog. If the portal later denies a capture, the marker is
+//! removed and the next capture asks again.
+
+use crate::capture::CaptureSource;
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use image::DynamicImage;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use url::Url;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+use zbus::{dbus_proxy, Connection};
+
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+
+/// How long to wait for the portal to answer a request. Interactive requests wait for the user.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+const INTERACTIVE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(60 * 5);
+
+#[dbus_proxy(
+    interface = "org.freedesktop.portal.Screenshot",
+    default_service = "org.freedesktop.portal.Desktop",
+    default_path = "/org/freedesktop/portal/desktop"
+)]
+trait Screenshot {
+    fn screenshot(
+        &self,
+        parent_window: &str,
+        options: HashMap<&str, Value<'_>>,
+    ) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.portal.Request",
+    default_service = "org.freedesktop.portal.Desktop"
+)]
+trait Request {
+    #[dbus_proxy(signal)]
+    fn response(&self, response: u32, results: HashMap<String, OwnedValue>) -> zbus::Result<()>;
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum PortalError {
+    #[error("Screenshot permission was denied")]
+    Denied,
+
+    #[error("Screenshot portal failed with response code {0}")]
+    Failed(u32),
+
+    #[error("Screenshot portal did not respond within {0:?}")]
+    Timeout(Duration),
+
+    #[error("Screenshot portal returned an invalid uri: {0}")]
+    InvalidUri(String),
+}
+
+/// Captures the whole desktop through the screenshot portal on the session bus
+pub(crate) struct PortalCapture {
+    connection: Connection,
+    /// Marker which exists once the user has granted permission for non-interactive screenshots
+    granted_path: PathBuf,
+    requests: u64,
+}
+
+impl PortalCapture {
+    pub(crate) async fn new(granted_path: impl Into<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::with_connection(
+            Connection::session().await?,
+            granted_path,
+        ))
+    }
+
+    pub(crate) fn with_connection(
+        connection: Connection,
+        granted_path: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            connection,
+            granted_path: granted_path.into(),
+            requests: 0,
+        }
+    }
+
+    fn has_permission(&self) -> bool {
+        self.granted_path.exists()
+    }
+
+    /// Asks the portal for a screenshot and returns the uri of the file it was saved to
+    async fn request_screenshot(&mut self, interactive: bool) -> Result<String, Box<dyn Error>> {
+        self.requests += 1;
+        let handle_token = format!("open_accountability_{}", self.requests);
+        let sender = self
+            .connection
+            .unique_name()
+            .ok_or("Not connected to the session bus")?
+            .trim_start_matches(':')
+            .replace('.', "_");
+        let request_path = format!("{}/request/{}/{}", PORTAL_PATH, sender, handle_token);
+
+        // Subscribe before making the call, since the portal may respond before the call returns
+        let request = RequestProxy::builder(&self.connection)
+            .path(request_path)?
+            .build()
+            .await?;
+        let mut responses = request.receive_response().await?;
+
+        let options = HashMap::from([
+            ("handle_token", Value::from(handle_token.as_str())),
+            ("interactive", Value::from(interactive)),
+        ]);
+        let handle = ScreenshotProxy::new(&self.connection)
+            .await?
+            .screenshot("", options)
+            .await?;
+        if handle.as_str() != request.path().as_str() {
+            // Portals older than version 0.9 ignore the handle token
+            warn!(
+                "Portal replied on unexpected request path {}",
+                handle.as_str()
+            );
+            let request = RequestProxy::builder(&self.connection)
+                .path(handle)?
+                .build()
+                .await?;
+            responses = request.receive_response().await?;
+        }
+
+        let timeout = if interactive {
+            INTERACTIVE_RESPONSE_TIMEOUT
+        } else {
+            RESPONSE_TIMEOUT
+        };
+        let response = tokio::time::timeout(timeout, responses.next())
+            .await
+            .map_err(|_| PortalError::Timeout(timeout))?
+            .ok_or("Portal request closed without a response")?;
+        let args = response.args()?;
+        match args.response {
+            0 => {}
+            1 => return Err(PortalError::Denied.into()),
+            code => return Err(PortalError::Failed(code).into()),
+        }
+
+        let uri = args
+            .results
+            .get("uri")
+            .ok_or_else(|| PortalError::InvalidUri("".to_string()))?;
+        Ok(String::try_from(uri.clone())?)
+    }
+
+    /// Records when the user granted permission
+    fn mark_granted(&self) -> std::io::Result<()> {
+        let granted_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        fs::write(&self.granted_path, granted_at.to_string())
+    }
+}
+
+/// Reads the screenshot the portal saved and removes it, so that screenshots are never left on
+/// disk
+fn take_screenshot_file(uri: &str) -> Result<DynamicImage, Box<dyn Error>> {
+    let path = Url::parse(uri)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .ok_or_else(|| PortalError::InvalidUri(uri.to_string()))?;
+    let contents = fs::read(&path);
+    if let Err(e) = fs::remove_file(&path) {
+        warn!("Failed to remove portal screenshot {:?}: {}", path, e);
+    }
+    Ok(image::load_from_memory(&contents?)?)
+}
+
+#[async_trait]
+impl CaptureSource for PortalCapture {
+    async fn capture(&mut self) -> Result<Vec<DynamicImage>, Box<dyn Error>> {
+        let interactive = !self.has_permission();
+        if interactive {
+            info!("Asking for permission to take screenshots through the portal");
+        }
+
+        let uri = match self.request_screenshot(interactive).await {
+            Ok(uri) => uri,
+            Err(e) => {
+                if matches!(e.downcast_ref(), Some(PortalError::Denied)) && !interactive {
+                    warn!("Screenshot permission was revoked, asking again on the next capture");
+                    fs::remove_file(&self.granted_path)?;
+                }
+                return Err(e);
+            }
+        };
+        if interactive {
+            self.mark_granted()?;
+        }
+
+        Ok(vec![take_screenshot_file(&uri)?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_bus::TestBus;
+    use crate::test_util::temp_dir;
+    use pretty_assertions::assert_eq;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+    use zbus::{dbus_interface, ConnectionBuilder, MessageHeader};
+
+    /// Stands in for xdg-desktop-portal: answers each screenshot request with a copy of
+    /// `tests/test_image.png`, or with `response` if it is not 0
+    struct MockPortal {
+        /// Where screenshots are saved
+        dir: PathBuf,
+        response: Arc<Mutex<u32>>,
+        interactive: Arc<Mutex<Vec<bool>>>,
+        files: Arc<Mutex<Vec<PathBuf>>>,
+    }
+
+    #[dbus_interface(name = "org.freedesktop.portal.Screenshot")]
+    impl MockPortal {
+        async fn screenshot(
+            &self,
+            #[zbus(header)] header: MessageHeader<'_>,
+            #[zbus(connection)] connection: &Connection,
+            _parent_window: &str,
+            options: HashMap<String, OwnedValue>,
+        ) -> zbus::fdo::Result<OwnedObjectPath> {
+            let sender = header.sender()?.unwrap().to_owned();
+            let handle_token = String::try_from(options["handle_token"].clone()).unwrap();
+            let interactive = bool::try_from(options["interactive"].clone()).unwrap();
+            self.interactive.lock().unwrap().push(interactive);
+
+            let path = format!(
+                "{}/request/{}/{}",
+                PORTAL_PATH,
+                sender.trim_start_matches(':').replace('.', "_"),
+                handle_token
+            );
+            let file = self.dir.join(format!("screenshot-{}.png", handle_token));
+            fs::copy("tests/test_image.png", &file).unwrap();
+            self.files.lock().unwrap().push(file.clone());
+
+            let response = *self.response.lock().unwrap();
+            let uri = Url::from_file_path(&file).unwrap().to_string();
+            let results = HashMap::from([("uri", Value::from(uri.as_str()))]);
+            connection
+                .emit_signal(
+                    Some(sender.as_str()),
+                    path.as_str(),
+                    "org.freedesktop.portal.Request",
+                    "Response",
+                    &(response, results),
+                )
+                .await?;
+            Ok(OwnedObjectPath::try_from(path).unwrap())
+        }
+    }
+
+    struct Fixture {
+        _dir: TempDir,
+        _bus: TestBus,
+        _portal: Connection,
+        capture: PortalCapture,
+        response: Arc<Mutex<u32>>,
+        interactive: Arc<Mutex<Vec<bool>>>,
+        files: Arc<Mutex<Vec<PathBuf>>>,
+    }
+
+    async fn fixture() -> Fixture {
+        let bus = TestBus::start();
+        let dir = temp_dir();
+        let response = Arc::new(Mutex::new(0));
+        let interactive = Arc::new(Mutex::new(Vec::new()));
+        let files = Arc::new(Mutex::new(Vec::new()));
+        let portal = ConnectionBuilder::address(bus.address.as_str())
+            .unwrap()
+            .name("org.freedesktop.portal.Desktop")
+            .unwrap()
+            .serve_at(
+                PORTAL_PATH,
+                MockPortal {
+                    dir: dir.path().to_path_buf(),
+                    response: response.clone(),
+                    interactive: interactive.clone(),
+                    files: files.clone(),
+                },
+            )
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let granted_path = dir.path().join("portal-permission");
+        let capture = PortalCapture::with_connection(bus.connect().await, granted_path);
+        Fixture {
+            _dir: dir,
+            _bus: bus,
+            _portal: portal,
+            capture,
+            response,
+            interactive,
+            files,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_permission_persists_across_restarts() {
+        let mut fixture = fixture().await;
+
+        let images = fixture.capture.capture().await.unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!((images[0].width(), images[0].height()), (133, 413));
+        assert!(fixture.capture.has_permission());
+
+        // A restarted daemon reuses the stored permission
+        let mut restarted = PortalCapture::with_connection(
+            fixture.capture.connection.clone(),
+            fixture.capture.granted_path.clone(),
+        );
+        restarted.capture().await.unwrap();
+        assert_eq!(*fixture.interactive.lock().unwrap(), vec![true, false]);
+
+        // Screenshots are not left behind on disk
+        for file in fixture.files.lock().unwrap().iter() {
+            assert!(!file.exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revoked_permission_asks_again() {
+        let mut fixture = fixture().await;
+        fixture.capture.capture().await.unwrap();
+
+        *fixture.response.lock().unwrap() = 1;
+        let err = fixture.capture.capture().await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(PortalError::Denied)));
+        assert!(!fixture.capture.has_permission());
+
+        // Denying the interactive request doesn't mark permission as granted
+        assert!(fixture.capture.capture().await.is_err());
+        assert!(!fixture.capture.has_permission());
+        assert_eq!(
+            *fixture.interactive.lock().unwrap(),
+            vec![true, false, true]
+        );
+    }
+}
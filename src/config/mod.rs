@@ -0,0 +1,453 @@
+//! Runtime configuration.
+//!
+//! Settings are read from the system config file, `/etc/open-accountability/config.toml`, which
+//! must be owned and only writable by root. The user's own config file (the path in
+//! `OPEN_ACCOUNTABILITY_CONFIG`, or `$XDG_CONFIG_HOME/open-accountability/config.toml`) and
+//! `OPEN_ACCOUNTABILITY_<SETTING>` environment variables can then override the few settings in
+//! [`USER_SETTINGS`]. Everything else decides what is captured and where it is reported, so the
+//! user being watched can't change it.
+//!
+//! That includes `api_base_url` and `api_key`: staging and production are switched in the system
+//! file. The daemon can't tell whether its environment came from the root-owned service unit or
+//! from the user starting it by hand, so the environment can't be trusted with them either. The
+//! install script writes the capture source into the system file for the same reason.
+//!
+//! Settings missing from all of them fall back to the defaults in `main.rs`.
+
+use crate::{
+    API_BASE_URL, LOG_FILE_LINE_COUNT_LIMIT, LOG_PATH, MAX_SLEEP_SECONDS, MIN_SLEEP_SECONDS,
+    OCR_SLICE_HEIGHT, OCR_SLICE_OVERLAP, OCR_SOURCE_RESOLUTION, SERVICE_NAME,
+};
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Prefix of the environment variables which override settings
+const ENV_PREFIX: &str = "OPEN_ACCOUNTABILITY_";
+
+/// Environment variable naming the config file to use
+const CONFIG_PATH_VAR: &str = "OPEN_ACCOUNTABILITY_CONFIG";
+
+const SYSTEM_CONFIG_DIR: &str = "/etc";
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Settings which may be changed from the user's config file or the environment
+const USER_SETTINGS: [&str; 2] = ["log_path", "log_line_limit"];
+const SYSTEM_STATE_DIR: &str = "/var/lib";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid config file {path:?}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("Unknown setting in environment variable {0}")]
+    UnknownEnv(String),
+
+    #[error("Invalid value {value:?} in environment variable {name}")]
+    EnvValue { name: String, value: String },
+
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+
+    #[error("Config file {0:?} must be owned by root and writable only by root")]
+    Untrusted(PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Base URL of the OpenAccountability server
+    pub(crate) api_base_url: String,
+    /// Firebase web API key, used to refresh id tokens
+    pub(crate) api_key: String,
+    /// Bounds of the random wait between captures
+    pub(crate) min_sleep_seconds: u64,
+    pub(crate) max_sleep_seconds: u64,
+    pub(crate) log_path: PathBuf,
+    /// The log is trimmed once it grows past this many lines
+    pub(crate) log_line_limit: usize,
+    pub(crate) device_info_path: PathBuf,
+    pub(crate) event_queue_path: PathBuf,
+    /// The last blacklist fetched, used when starting up without a connection
+    pub(crate) blacklist_cache_path: PathBuf,
+    /// Records how each run ended. See [`crate::journal`].
+    pub(crate) journal_path: PathBuf,
+    /// Marker written once the user lets the screenshot portal capture without asking
+    pub(crate) portal_permission_path: PathBuf,
+    /// Where screen images come from. See [`crate::capture::from_spec`].
+    pub(crate) capture: String,
+    /// Height of the slices screenshots are cut into for OCR, and how much they overlap
+    pub(crate) ocr_slice_height: u32,
+    pub(crate) ocr_slice_overlap: u32,
+    /// Resolution tesseract assumes for screenshots, in pixels per inch
+    pub(crate) ocr_source_resolution: i32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            api_base_url: API_BASE_URL.to_string(),
+            // Release builds may bake in the production key
+            api_key: option_env!("API_KEY").unwrap_or_default().to_string(),
+            min_sleep_seconds: MIN_SLEEP_SECONDS,
+            max_sleep_seconds: MAX_SLEEP_SECONDS,
+            log_path: PathBuf::from(LOG_PATH),
+            log_line_limit: LOG_FILE_LINE_COUNT_LIMIT,
+            device_info_path: state_dir().join("device"),
+            event_queue_path: state_dir().join("events"),
+            blacklist_cache_path: state_dir().join("blacklist.json"),
+            journal_path: state_dir().join("journal"),
+            portal_permission_path: state_dir().join("portal-permission"),
+            capture: String::new(),
+            ocr_slice_height: OCR_SLICE_HEIGHT,
+            ocr_slice_overlap: OCR_SLICE_OVERLAP,
+            ocr_source_resolution: OCR_SOURCE_RESOLUTION,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the system and user config files from their standard locations and applies
+    /// environment overrides
+    pub(crate) fn load() -> Result<Self, ConfigError> {
+        let system_path = Path::new(SYSTEM_CONFIG_DIR)
+            .join(SERVICE_NAME)
+            .join(CONFIG_FILE_NAME);
+        let system = match system_path.exists() {
+            true => {
+                check_root_owned(&system_path)?;
+                eprintln!("Loading config from {:?}", system_path);
+                read_table(&system_path)?
+            }
+            false => toml::Table::new(),
+        };
+
+        let user_path = match std::env::var_os(CONFIG_PATH_VAR) {
+            // An explicitly requested file must exist
+            Some(path) => Some(PathBuf::from(path)),
+            None => user_config_path().filter(|path| path.exists()),
+        };
+        let user = match &user_path {
+            Some(path) => {
+                eprintln!("Loading user config from {:?}", path);
+                read_table(path)?
+            }
+            None => toml::Table::new(),
+        };
+        Self::from_sources(system, user, std::env::vars())
+    }
+
+    /// Builds the config from the system file's settings, then the user file's and the
+    /// environment's overrides of [`USER_SETTINGS`]
+    fn from_sources(
+        mut table: toml::Table,
+        user: toml::Table,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let toml::Value::Table(defaults) =
+            toml::Value::try_from(Config::default()).expect("Config serializes to a table")
+        else {
+            unreachable!("Config serializes to a table");
+        };
+        let ignore = |key: &str, source: &str| {
+            eprintln!(
+                "Ignoring {} from {}, it can only be set in the system config file",
+                key, source
+            );
+        };
+
+        for (key, value) in user {
+            // Unknown settings are kept, so that they are rejected below
+            if defaults.contains_key(&key) && !USER_SETTINGS.contains(&key.as_str()) {
+                ignore(&key, "the user config file");
+                continue;
+            }
+            table.insert(key, value);
+        }
+
+        for (name, value) in env {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if name == CONFIG_PATH_VAR {
+                continue;
+            }
+            let key = key.to_lowercase();
+            if defaults.contains_key(&key) && !USER_SETTINGS.contains(&key.as_str()) {
+                ignore(&key, &name);
+                continue;
+            }
+            let value = match defaults.get(&key) {
+                None => return Err(ConfigError::UnknownEnv(name)),
+                Some(toml::Value::String(_)) => toml::Value::String(value),
+                Some(_) => parse_env_value(&value).ok_or(ConfigError::EnvValue {
+                    name: name.clone(),
+                    value,
+                })?,
+            };
+            table.insert(key, value);
+        }
+
+        let config: Config = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e: toml::de::Error| ConfigError::Invalid(e.message().to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Invalid(message));
+
+        match url::Url::parse(&self.api_base_url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => return invalid(format!("api_base_url {:?} is not a URL", self.api_base_url)),
+        }
+        if self.api_key.is_empty() {
+            return invalid("api_key must be set".to_string());
+        }
+        if self.min_sleep_seconds == 0 || self.min_sleep_seconds > self.max_sleep_seconds {
+            return invalid(format!(
+                "min_sleep_seconds ({}) must be at least 1 and at most max_sleep_seconds ({})",
+                self.min_sleep_seconds, self.max_sleep_seconds
+            ));
+        }
+        if self.log_line_limit == 0 {
+            return invalid("log_line_limit must be at least 1".to_string());
+        }
+        if self.ocr_slice_overlap >= self.ocr_slice_height {
+            return invalid(format!(
+                "ocr_slice_overlap ({}) must be less than ocr_slice_height ({})",
+                self.ocr_slice_overlap, self.ocr_slice_height
+            ));
+        }
+        if self.ocr_source_resolution <= 0 {
+            return invalid("ocr_source_resolution must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Where the daemon keeps its own files: `$XDG_STATE_HOME/open-accountability` (or
+/// `~/.local/state/...`), or `/var/lib/open-accountability` for a service without a home
+/// directory
+fn state_dir() -> PathBuf {
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .unwrap_or_else(|| PathBuf::from(SYSTEM_STATE_DIR))
+        .join(SERVICE_NAME)
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join(SERVICE_NAME).join(CONFIG_FILE_NAME))
+}
+
+/// Fails unless only root can change the file at `path`
+fn check_root_owned(path: &Path) -> Result<(), ConfigError> {
+    let metadata = std::fs::metadata(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+        return Err(ConfigError::Untrusted(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+fn read_table(path: &Path) -> Result<toml::Table, ConfigError> {
+    let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    contents.parse().map_err(|source| ConfigError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Parses a non-string setting from an environment variable as a TOML value
+fn parse_env_value(value: &str) -> Option<toml::Value> {
+    let mut table: toml::Table = format!("value = {}", value).parse().ok()?;
+    table.remove("value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_dir;
+    use pretty_assertions::assert_eq;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn table(toml: &str) -> toml::Table {
+        toml.parse().unwrap()
+    }
+
+    fn error(result: Result<Config, ConfigError>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    /// The user can only override their own settings, not what is captured or reported
+    #[test]
+    fn test_file_and_env_overrides() {
+        let config = Config::from_sources(
+            table(
+                r#"
+                api_base_url = "https://staging.example.com"
+                api_key = "file-key"
+                max_sleep_seconds = 600
+                "#,
+            ),
+            table(
+                r#"
+                api_base_url = "https://attacker.example.com"
+                capture = "synthetic"
+                log_line_limit = 50
+                "#,
+            ),
+            env(&[
+                ("OPEN_ACCOUNTABILITY_API_KEY", "12345"),
+                ("OPEN_ACCOUNTABILITY_MIN_SLEEP_SECONDS", "30"),
+                // Numeric-looking strings stay strings
+                ("OPEN_ACCOUNTABILITY_LOG_PATH", "12345"),
+                ("OPEN_ACCOUNTABILITY_CONFIG", "/unused"),
+                ("PATH", "/usr/bin"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                api_base_url: "https://staging.example.com".to_string(),
+                api_key: "file-key".to_string(),
+                max_sleep_seconds: 600,
+                log_path: PathBuf::from("12345"),
+                log_line_limit: 50,
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_unknown_settings_rejected() {
+        assert_eq!(
+            error(Config::from_sources(
+                table("api_key = \"key\""),
+                table("slice_height = 256"),
+                env(&[]),
+            )),
+            "Invalid config: unknown field `slice_height`, expected one of `api_base_url`, \
+             `api_key`, `min_sleep_seconds`, `max_sleep_seconds`, `log_path`, \
+             `log_line_limit`, `device_info_path`, `event_queue_path`, `blacklist_cache_path`, \
+             `journal_path`, `portal_permission_path`, `capture`, `ocr_slice_height`, \
+             `ocr_slice_overlap`, `ocr_source_resolution`"
+        );
+        assert_eq!(
+            error(Config::from_sources(
+                table("api_key = \"key\""),
+                toml::Table::new(),
+                env(&[("OPEN_ACCOUNTABILITY_SLEEP", "5")]),
+            )),
+            "Unknown setting in environment variable OPEN_ACCOUNTABILITY_SLEEP"
+        );
+    }
+
+    #[test]
+    fn test_invalid_values_rejected() {
+        assert_eq!(
+            error(Config::from_sources(
+                table("api_key = \"key\""),
+                toml::Table::new(),
+                env(&[("OPEN_ACCOUNTABILITY_LOG_LINE_LIMIT", "long")]),
+            )),
+            "Invalid value \"long\" in environment variable OPEN_ACCOUNTABILITY_LOG_LINE_LIMIT"
+        );
+        assert_eq!(
+            error(Config::from_sources(
+                table("api_key = \"key\"\nmax_sleep_seconds = \"10\""),
+                toml::Table::new(),
+                env(&[]),
+            )),
+            "Invalid config: invalid type: string \"10\", expected u64"
+        );
+        assert_eq!(
+            error(Config::from_sources(
+                table("api_key = \"key\"\nmin_sleep_seconds = 600"),
+                toml::Table::new(),
+                env(&[]),
+            )),
+            "Invalid config: min_sleep_seconds (600) must be at least 1 and at most \
+             max_sleep_seconds (300)"
+        );
+        assert_eq!(
+            error(Config::from_sources(
+                table("api_key = \"key\"\nocr_slice_overlap = 512"),
+                toml::Table::new(),
+                env(&[]),
+            )),
+            "Invalid config: ocr_slice_overlap (512) must be less than ocr_slice_height (512)"
+        );
+        assert_eq!(
+            error(Config::from_sources(
+                table("api_key = \"key\"\napi_base_url = \"staging\""),
+                toml::Table::new(),
+                env(&[]),
+            )),
+            "Invalid config: api_base_url \"staging\" is not a URL"
+        );
+        assert_eq!(
+            error(Config::from_sources(
+                table("api_key = \"\""),
+                toml::Table::new(),
+                env(&[]),
+            )),
+            "Invalid config: api_key must be set"
+        );
+    }
+
+    #[test]
+    fn test_system_file_must_be_root_owned() {
+        let dir = temp_dir();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(matches!(
+            check_root_owned(&path),
+            Err(ConfigError::Untrusted(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_file() {
+        let dir = temp_dir();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "capture = \"synthetic\"\n").unwrap();
+        assert_eq!(read_table(&path).unwrap(), table("capture = \"synthetic\""));
+
+        std::fs::write(&path, "capture = ").unwrap();
+        assert!(matches!(read_table(&path), Err(ConfigError::Parse { .. })));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(read_table(&path), Err(ConfigError::Read { .. })));
+    }
+}
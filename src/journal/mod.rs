@@ -0,0 +1,452 @@
+//! The run journal, which tells how the previous run of the daemon ended.
+//!
+//! Each run appends a start marker, a heartbeat every minute, and finally a clean-exit, error or
+//! panic marker. On startup the previous run's journal is classified before it is replaced: a
+//! run without a final marker was killed if the machine is still on the same boot, or if its
+//! last heartbeat came well before the current boot began. Otherwise the machine lost power (or
+//! the kernel crashed) while the daemon was running. The boot id and uptime recorded with every
+//! marker are checked against the current ones, so an edited journal shows up as inconsistent.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::panic::PanicHookInfo;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+const UPTIME_PATH: &str = "/proc/uptime";
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A run whose last heartbeat came more than this long before the current boot began had
+/// already stopped when the machine went down
+const POWER_LOSS_MARGIN: Duration = Duration::from_secs(2 * HEARTBEAT_INTERVAL.as_secs());
+
+/// Once the journal holds this many records, it is rewritten with just the start marker
+const MAX_RECORDS: usize = 1000;
+
+/// The boot the daemon is running in
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Boot {
+    pub(crate) id: String,
+    /// Time since the machine booted
+    pub(crate) uptime: Duration,
+    /// When the machine booted, in milliseconds since the Unix epoch
+    pub(crate) started_at: u64,
+}
+
+impl Boot {
+    pub(crate) fn current() -> io::Result<Self> {
+        let uptime = uptime()?;
+        Ok(Self {
+            id: fs::read_to_string(BOOT_ID_PATH)?.trim().to_string(),
+            uptime,
+            started_at: now_millis().saturating_sub(uptime.as_millis() as u64),
+        })
+    }
+}
+
+/// Time since the machine booted, including time spent suspended
+pub(crate) fn uptime() -> io::Result<Duration> {
+    fs::read_to_string(UPTIME_PATH)?
+        .split_whitespace()
+        .next()
+        .and_then(|seconds| seconds.parse().ok())
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| io::Error::other(format!("Unexpected contents in {}", UPTIME_PATH)))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "marker", rename_all = "snake_case")]
+enum Marker {
+    Start { boot_id: String, pid: u32 },
+    Heartbeat,
+    CleanExit { shutdown: bool },
+    Error { message: String },
+    Panic { message: String },
+}
+
+/// One line of the journal
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Record {
+    /// When the record was written, in milliseconds since the Unix epoch
+    at: u64,
+    /// The machine's uptime when the record was written, in milliseconds
+    uptime_ms: u64,
+    #[serde(flatten)]
+    marker: Marker,
+}
+
+impl Record {
+    fn now(marker: Marker) -> io::Result<Self> {
+        Ok(Self {
+            at: now_millis(),
+            uptime_ms: uptime()?.as_millis() as u64,
+            marker,
+        })
+    }
+}
+
+/// How the previous run of the daemon ended
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ExitReason {
+    /// There is no journal, so this is the first run on this machine
+    FirstRun,
+    /// The daemon was stopped while the system kept running
+    Stopped,
+    /// The daemon stopped for a system shutdown or reboot
+    Shutdown,
+    /// The daemon stopped after an error
+    Error,
+    /// The daemon panicked
+    Panic,
+    /// The daemon disappeared without a trace while the system kept running, e.g. `kill -9` or
+    /// the OOM killer
+    Killed,
+    /// The system went down without the daemon noticing, e.g. power loss or a kernel panic
+    PowerLoss,
+    /// The journal contradicts the boot id or uptime, e.g. because it was edited
+    Inconsistent,
+}
+
+/// The classification of the previous run, as reported to the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct PreviousExit {
+    pub(crate) reason: ExitReason,
+    /// When the previous run was last known to be alive, in milliseconds since the Unix epoch
+    pub(crate) last_seen: Option<u64>,
+}
+
+/// Classifies the previous run from its journal and the boot the daemon is starting in
+fn classify(records: &[Record], boot: &Boot) -> ExitReason {
+    let (Some(first), Some(last)) = (records.first(), records.last()) else {
+        return ExitReason::FirstRun;
+    };
+    let Marker::Start { boot_id, .. } = &first.marker else {
+        return ExitReason::Inconsistent;
+    };
+    let same_boot = *boot_id == boot.id;
+
+    // Within one boot, uptime only ever grows
+    let uptime_ms = records.iter().map(|record| record.uptime_ms);
+    if !uptime_ms
+        .clone()
+        .zip(uptime_ms.skip(1))
+        .all(|(a, b)| a <= b)
+        || (same_boot && last.uptime_ms > boot.uptime.as_millis() as u64)
+    {
+        return ExitReason::Inconsistent;
+    }
+
+    match &last.marker {
+        // A shutdown which the machine never went through
+        Marker::CleanExit { shutdown: true } if same_boot => ExitReason::Inconsistent,
+        Marker::CleanExit { shutdown: true } => ExitReason::Shutdown,
+        Marker::CleanExit { shutdown: false } => ExitReason::Stopped,
+        Marker::Error { .. } => ExitReason::Error,
+        Marker::Panic { .. } => ExitReason::Panic,
+        Marker::Start { .. } | Marker::Heartbeat if same_boot => ExitReason::Killed,
+        // Stopped long before the machine went down, e.g. killed and then rebooted later
+        Marker::Start { .. } | Marker::Heartbeat
+            if last.at + (POWER_LOSS_MARGIN.as_millis() as u64) < boot.started_at =>
+        {
+            ExitReason::Killed
+        }
+        Marker::Start { .. } | Marker::Heartbeat => ExitReason::PowerLoss,
+    }
+}
+
+/// Reads the records of a journal. A torn last line, from a crash part way through a write, is
+/// skipped.
+fn read_records(path: &Path) -> io::Result<Vec<Record>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                warn!("Skipping unreadable journal line {:?}: {}", line, e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// The journal of the current run. Clones write to the same journal.
+#[derive(Clone)]
+pub(crate) struct Journal {
+    inner: Arc<Mutex<JournalInner>>,
+}
+
+struct JournalInner {
+    path: PathBuf,
+    file: File,
+    start: Record,
+    records: usize,
+}
+
+impl JournalInner {
+    fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Rewrites the journal with just the start marker, so that it doesn't grow forever
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(
+            &tmp_path,
+            [serde_json::to_vec(&self.start)?, vec![b'\n']].concat(),
+        )?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = 1;
+        Ok(())
+    }
+}
+
+impl Journal {
+    /// Classifies how the previous run ended, then starts this run's journal at `path`
+    pub(crate) fn start(path: &Path, boot: &Boot) -> io::Result<(Self, PreviousExit)> {
+        let records = read_records(path)?;
+        let previous = PreviousExit {
+            reason: classify(&records, boot),
+            last_seen: records.last().map(|record| record.at),
+        };
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let start = Record {
+            at: now_millis(),
+            uptime_ms: boot.uptime.as_millis() as u64,
+            marker: Marker::Start {
+                boot_id: boot.id.clone(),
+                pid: std::process::id(),
+            },
+        };
+        let mut inner = JournalInner {
+            path: path.to_path_buf(),
+            file: File::create(path)?,
+            start: start.clone(),
+            records: 0,
+        };
+        inner.append(&start)?;
+        let journal = Self {
+            inner: Arc::new(Mutex::new(inner)),
+        };
+        Ok((journal, previous))
+    }
+
+    fn record(&self, marker: Marker) -> io::Result<()> {
+        let record = Record::now(marker)?;
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if inner.records >= MAX_RECORDS {
+            inner.compact()?;
+        }
+        inner.append(&record)
+    }
+
+    pub(crate) fn heartbeat(&self) -> io::Result<()> {
+        self.record(Marker::Heartbeat)
+    }
+
+    /// Marks that the daemon is exiting normally, for a system shutdown if `shutdown` is set
+    pub(crate) fn clean_exit(&self, shutdown: bool) -> io::Result<()> {
+        self.record(Marker::CleanExit { shutdown })
+    }
+
+    /// Marks that the daemon is exiting because of `error`
+    pub(crate) fn error(&self, error: &dyn std::error::Error) -> io::Result<()> {
+        self.record(Marker::Error {
+            message: error.to_string(),
+        })
+    }
+
+    /// Marks panics in the journal before they are reported as usual
+    pub(crate) fn install_panic_hook(&self) {
+        let journal = self.clone();
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info: &PanicHookInfo| {
+            // The journal's lock may be held by the panicking thread, so it is only tried
+            if let (Ok(record), Ok(mut inner)) = (
+                Record::now(Marker::Panic {
+                    message: info.to_string(),
+                }),
+                journal.inner.try_lock(),
+            ) {
+                let _ = inner.append(&record);
+            }
+            default_hook(info);
+        }));
+    }
+}
+
+/// Writes a heartbeat every minute for as long as the program runs
+pub(crate) async fn run_heartbeats(journal: Journal) {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = journal.heartbeat() {
+            warn!("Failed to write a heartbeat to the run journal: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_dir;
+    use pretty_assertions::assert_eq;
+
+    /// When boot `a`, which the test runs start on, began
+    const BOOT_A_STARTED_AT: u64 = 1_700_000_000_000;
+
+    /// A boot which began `started_secs` after boot `a`, and has been up for `uptime_secs`
+    fn boot(id: &str, started_secs: u64, uptime_secs: u64) -> Boot {
+        Boot {
+            id: id.to_string(),
+            uptime: Duration::from_secs(uptime_secs),
+            started_at: BOOT_A_STARTED_AT + started_secs * 1000,
+        }
+    }
+
+    fn record(uptime_secs: u64, marker: Marker) -> Record {
+        Record {
+            at: BOOT_A_STARTED_AT + uptime_secs * 1000,
+            uptime_ms: uptime_secs * 1000,
+            marker,
+        }
+    }
+
+    /// A run which started on boot `a` and ended with `last`
+    fn run(last: Option<Marker>) -> Vec<Record> {
+        let mut records = vec![
+            record(
+                10,
+                Marker::Start {
+                    boot_id: "a".to_string(),
+                    pid: 1,
+                },
+            ),
+            record(70, Marker::Heartbeat),
+        ];
+        records.extend(last.map(|marker| record(80, marker)));
+        records
+    }
+
+    #[test]
+    fn test_classify() {
+        let same_boot = boot("a", 0, 100);
+        // The machine went down within a minute of the last heartbeat
+        let new_boot = boot("b", 100, 5);
+        // The machine went down hours after the last heartbeat
+        let much_later_boot = boot("b", 4 * 60 * 60, 5);
+        let cases = [
+            (vec![], &new_boot, ExitReason::FirstRun),
+            (run(None), &same_boot, ExitReason::Killed),
+            (run(None), &new_boot, ExitReason::PowerLoss),
+            (run(None), &much_later_boot, ExitReason::Killed),
+            (
+                run(Some(Marker::CleanExit { shutdown: false })),
+                &same_boot,
+                ExitReason::Stopped,
+            ),
+            (
+                run(Some(Marker::CleanExit { shutdown: true })),
+                &new_boot,
+                ExitReason::Shutdown,
+            ),
+            (
+                run(Some(Marker::Panic {
+                    message: "oops".to_string(),
+                })),
+                &new_boot,
+                ExitReason::Panic,
+            ),
+            (
+                run(Some(Marker::Error {
+                    message: "no network".to_string(),
+                })),
+                &same_boot,
+                ExitReason::Error,
+            ),
+            // A shutdown without a reboot
+            (
+                run(Some(Marker::CleanExit { shutdown: true })),
+                &same_boot,
+                ExitReason::Inconsistent,
+            ),
+            // The journal claims more uptime than the boot has had
+            (run(None), &boot("a", 0, 50), ExitReason::Inconsistent),
+            // No start marker
+            (
+                run(None)[1..].to_vec(),
+                &same_boot,
+                ExitReason::Inconsistent,
+            ),
+        ];
+        for (records, boot, reason) in cases {
+            assert_eq!(classify(&records, boot), reason, "{:?}", records);
+        }
+    }
+
+    #[test]
+    fn test_journal_across_runs() {
+        let dir = temp_dir();
+        let path = dir.path().join("journal");
+        let boot = Boot::current().unwrap();
+
+        let (journal, previous) = Journal::start(&path, &boot).unwrap();
+        assert_eq!(previous.reason, ExitReason::FirstRun);
+        assert_eq!(previous.last_seen, None);
+        journal.heartbeat().unwrap();
+        drop(journal);
+
+        // Nothing marked the end of the run
+        let (journal, previous) = Journal::start(&path, &Boot::current().unwrap()).unwrap();
+        assert_eq!(previous.reason, ExitReason::Killed);
+        assert!(previous.last_seen.is_some());
+        journal.clean_exit(false).unwrap();
+
+        // A torn write after the exit marker is ignored
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"at\":17").unwrap();
+        let (_, previous) = Journal::start(&path, &Boot::current().unwrap()).unwrap();
+        assert_eq!(previous.reason, ExitReason::Stopped);
+    }
+
+    #[test]
+    fn test_journal_is_compacted() {
+        let dir = temp_dir();
+        let path = dir.path().join("journal");
+        let (journal, _) = Journal::start(&path, &Boot::current().unwrap()).unwrap();
+        for _ in 0..MAX_RECORDS + 10 {
+            journal.heartbeat().unwrap();
+        }
+
+        let records = read_records(&path).unwrap();
+        assert!(records.len() <= MAX_RECORDS);
+        assert!(matches!(records[0].marker, Marker::Start { .. }));
+        assert_eq!(records.last().unwrap().marker, Marker::Heartbeat);
+    }
+}
@@ -1,14 +1,28 @@
 #[macro_use]
 extern crate rocket;
 
+mod attestation;
 mod auth;
+mod capture;
+mod config;
+mod journal;
 mod monitoring;
+mod power;
+mod queue;
 mod requests;
+mod scheduler;
+mod tamper;
+#[cfg(test)]
+mod test_bus;
+#[cfg(test)]
+mod test_util;
 
 use signal_hook::consts::SIGTERM;
 use signal_hook::iterator::Signals;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
 
 use file_rotate::{
     compression::Compression, suffix::AppendCount, ContentLimit, FileRotate, TimeFrequency,
@@ -43,6 +57,9 @@ pub enum OpenAccError {
     #[error("reqwest error")]
     ReqwestError(#[from] reqwest::Error),
 
+    #[error("Background task failed")]
+    JoinError(#[from] tokio::task::JoinError),
+
     // put all other errors into one
     #[error("Other error")]
     OtherError(#[from] Box<dyn Error>),
@@ -50,8 +67,12 @@ pub enum OpenAccError {
 
 const SERVICE_NAME: &str = "open-accountability";
 
-#[macro_use]
-extern crate dotenv_codegen;
+// Signs in with a code approved from another device, instead of opening a browser
+const DEVICE_CODE_FLAG: &str = "--device-code";
+
+// Defaults for the settings in the config file. See the config module.
+
+const API_BASE_URL: &str = "https://us-central1-openaccountability.cloudfunctions.net";
 
 const MIN_SLEEP_SECONDS: u64 = 60 * 2;
 const MAX_SLEEP_SECONDS: u64 = 60 * 5;
@@ -61,14 +82,34 @@ const LOG_FILE_LINE_COUNT_LIMIT: usize = 10000;
 
 const LOG_PATH: &str = "output.log";
 
+// Screenshots are OCR'd in horizontal slices of this height (in pixels), each sharing
+// OCR_SLICE_OVERLAP pixels with the slice before it
+const OCR_SLICE_HEIGHT: u32 = 512;
+const OCR_SLICE_OVERLAP: u32 = 64;
+
+// Resolution tesseract assumes for screenshots (in pixels per inch)
+const OCR_SOURCE_RESOLUTION: i32 = 100;
+
+// The device file older versions kept in the install directory, next to the binary, before it
+// moved to the state directory
+const LEGACY_DEVICE_FILE_NAME: &str = ".device";
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     log::info!("Starting up...");
 
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     // Setup up rotating loggers. Each log file will encompass at most one day, and there
     // will be up to three days of logs stored.
     let log = Box::new(FileRotate::new(
-        LOG_PATH.clone(),
+        config.log_path.clone(),
         AppendCount::new(3),
         ContentLimit::Time(TimeFrequency::Daily),
         Compression::None,
@@ -99,63 +140,223 @@ async fn main() -> Result<(), Box<dyn Error>> {
         // Apply globally
         .apply()?;
 
+    // Find out how the last run ended before this run's journal replaces it
+    let (journal, previous_exit) = Journal::start(&config.journal_path, &Boot::current()?)?;
+    journal.install_panic_hook();
+    info!("Previous run ended: {:?}", previous_exit.reason);
+    info!("Running {}", attestation::build_info());
+
+    // Every way out of the run leaves a marker, so that the next run knows how this one ended
+    let result = run(&config, &journal, previous_exit).await;
+    if let Err(e) = &result {
+        error!("Exiting on error: {}", e);
+        journal.error(e.as_ref())?;
+    }
+    result
+}
+
+async fn run(
+    config: &Config,
+    journal: &Journal,
+    previous_exit: PreviousExit,
+) -> Result<(), Box<dyn Error>> {
+    // Queued before anything else can fail, since the journal it was read from is already
+    // replaced and a failed startup must not hide how the previous run ended
+    let queue = EventQueue::open(&config.event_queue_path)?;
+    queue.push(&QueuedEvent::new(
+        SystemTime::now(),
+        Event::PreviousExit(previous_exit),
+    ))?;
+
     let lt = leptess::LepTess::new(None, "eng").unwrap();
+    let mut capture = capture::from_spec(&config.capture, &config.portal_permission_path).await?;
 
-    let client = reqwest::Client::new();
+    let api = Arc::new(HttpApi::new(
+        reqwest::Client::new(),
+        config.api_key.clone(),
+        &config.api_base_url,
+    ));
 
     // Authenticate the device
-    let mut auth = Auth::new(client).await?;
-    auth.check_service_file().await?;
+    let mut auth = Auth::new(
+        api,
+        config.device_info_path.clone(),
+        auth::sign_in_flow(
+            std::env::args().any(|arg| arg == DEVICE_CODE_FLAG),
+            reqwest::Client::new(),
+            &config.api_base_url,
+        ),
+    )
+    .await?;
+    auth.check_service_file(&queue).await?;
 
     // panic!("test");
     info!("Authenticated. About to start.");
 
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
+    // Cancelled on SIGINT or SIGTERM, which wakes up the monitor wherever it is waiting
+    let shutdown = CancellationToken::new();
+    let s = shutdown.clone();
     ctrlc::set_handler(move || {
         info!("Received SIGINT signal. Exiting...");
-        r.store(false, Ordering::SeqCst);
+        s.cancel();
     })
     .expect("Error setting Ctrl-C handler");
 
     // Register a handler for the SIGTERM signal
     let mut signals = Signals::new([SIGTERM]).unwrap();
-    let r2 = running.clone();
+    let s2 = shutdown.clone();
     std::thread::spawn(move || {
-        for _ in signals.forever() {
+        if signals.forever().next().is_some() {
             info!("Received SIGTERM signal. Exiting...");
-
-            // Handle the SIGTERM signal here
-            // Perform any cleanup tasks before exiting
-            r2.store(false, Ordering::SeqCst);
-            break;
+            s2.cancel();
         }
     });
+    let interval = UniformJitter {
+        min: Duration::from_secs(config.min_sleep_seconds),
+        max: Duration::from_secs(config.max_sleep_seconds),
+    };
+    let mut scheduler = Scheduler::new(shutdown, Box::new(interval));
+
+    for event in auth.startup_events.drain(..) {
+        queue.push(&event)?;
+    }
+    tokio::spawn(run_heartbeats(journal.clone()));
+
+    // Report changes to the files the daemon depends on as they happen
+    let watched_files = daemon_files(
+        &UnitPaths::system(),
+        &format!("{}.service", SERVICE_NAME),
+        &std::env::current_exe()?,
+        &config.device_info_path,
+        traineddata_path("eng"),
+    );
+    let file_watcher = match FileWatcher::new(watched_files) {
+        Ok(file_watcher) => Some(tokio::spawn(run_file_watcher(file_watcher, queue.clone()))),
+        Err(e) => {
+            warn!("Not watching files, inotify is unavailable: {}", e);
+            None
+        }
+    };
+
+    // Check for debuggers and injected libraries while running
+    tokio::spawn(run_process_checks(ProcessChecker::current(), queue.clone()));
 
-    match monitor(running, lt, &mut auth).await {
-        Ok(_) => {
-            auth.exit_program(false).await?;
+    // Get ready for shutdowns and suspends when logind announces them
+    let power_monitor = match zbus::Connection::system().await {
+        Ok(connection) => PowerMonitor::new(&connection).await,
+        Err(e) => Err(e),
+    };
+    let (shutdown_in_progress, sleeps) = match power_monitor {
+        Ok(power_monitor) => {
+            let shutdown_in_progress = power_monitor.shutdown_in_progress();
+            let sleeps = power_monitor.sleeps();
+            let handler = PrepareForPowerChange {
+                queue: queue.clone(),
+                api: auth.api.clone(),
+                credentials: auth.subscribe(),
+                device_path: auth.device_path.clone(),
+            };
+            tokio::spawn(async move {
+                if let Err(e) = power_monitor.run(handler).await {
+                    warn!("Stopped watching for shutdowns: {}", e);
+                }
+            });
+            (shutdown_in_progress, sleeps)
         }
-        Err(OpenAccError::SigTerm) => {
-            auth.exit_program(is_shutdown_in_progress()).await?;
+        Err(e) => {
+            warn!("Not watching for shutdowns, logind is unavailable: {}", e);
+            (Arc::default(), SleepLog::default())
         }
-        Err(_) => {}
     };
+    tokio::spawn(run_uploader(
+        queue.clone(),
+        auth.api.clone(),
+        auth.subscribe(),
+    ));
+
+    let result = monitor(
+        config,
+        &mut scheduler,
+        lt,
+        &mut auth,
+        &queue,
+        capture.as_mut(),
+        &mut SelfChecks {
+            gaps: GapDetector::new(sleeps),
+            canary: OcrCanary::new(CANARY_INTERVAL),
+        },
+    )
+    .await;
+    // Exiting removes or rewrites the device file, which shouldn't be reported
+    if let Some(file_watcher) = &file_watcher {
+        file_watcher.abort();
+    }
+    let shutdown_in_progress = match result {
+        Ok(_) => false,
+        Err(OpenAccError::SigTerm) => shutdown_in_progress.load(Ordering::SeqCst),
+        Err(e) => {
+            error!("Monitoring stopped: {}", e);
+            return Err(e.into());
+        }
+    };
+    // Marked first, since failing to report the exit doesn't make it any less clean
+    journal.clean_exit(shutdown_in_progress)?;
+    if let Err(e) = auth.exit_program(shutdown_in_progress).await {
+        error!("Failed to report the exit: {}", e);
+    }
 
     Ok(())
 }
 
 use crate::auth::Auth;
+use crate::config::Config;
 // use crate::monitoring::monitor;
 
-use crate::monitoring::monitor;
-use std::process::Command;
-
-fn is_shutdown_in_progress() -> bool {
-    let output = Command::new("runlevel")
-        .output()
-        .expect("Failed to execute command");
-    let output_str = String::from_utf8_lossy(&output.stdout);
-
-    output_str.starts_with("N 0")
+use crate::journal::{run_heartbeats, Boot, Journal, PreviousExit};
+use crate::monitoring::canary::{OcrCanary, CANARY_INTERVAL};
+use crate::monitoring::ocr::traineddata_path;
+use crate::monitoring::{monitor, SelfChecks};
+use crate::power::{PowerMonitor, PrepareForPowerChange, SleepLog};
+use crate::queue::{run_uploader, EventQueue, QueuedEvent};
+use crate::requests::api::HttpApi;
+use crate::requests::Event;
+use crate::scheduler::gaps::GapDetector;
+use crate::scheduler::{Scheduler, UniformJitter};
+use crate::tamper::process::{run_process_checks, ProcessChecker};
+use crate::tamper::unit::UnitPaths;
+use crate::tamper::watcher::{daemon_files, run_file_watcher, FileWatcher};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::journal::ExitReason;
+    use crate::test_util::temp_dir;
+    use pretty_assertions::assert_eq;
+
+    /// A startup which fails still queues how the previous run ended, so that making startup
+    /// fail can't turn a kill into an error
+    #[tokio::test]
+    async fn test_previous_exit_queued_when_startup_fails() {
+        let dir = temp_dir();
+        let config = Config {
+            event_queue_path: dir.path().join("events"),
+            journal_path: dir.path().join("journal"),
+            capture: "unknown".to_string(),
+            ..Config::default()
+        };
+        let (journal, _) = Journal::start(&config.journal_path, &Boot::current().unwrap()).unwrap();
+        let previous_exit = PreviousExit {
+            reason: ExitReason::Killed,
+            last_seen: Some(1_000),
+        };
+
+        assert!(run(&config, &journal, previous_exit.clone()).await.is_err());
+        let queue = EventQueue::open(&config.event_queue_path).unwrap();
+        let pending = queue.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        let queued: QueuedEvent =
+            serde_json::from_slice(&std::fs::read(&pending[0]).unwrap()).unwrap();
+        assert_eq!(queued.event, Event::PreviousExit(previous_exit));
+    }
 }
@@ -0,0 +1,260 @@
+use crate::requests::{BlacklistJson, EventReport, TierCounts};
+use aho_corasick::AhoCorasick;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// How severe a blacklisted keyword is, as assigned by the server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Tier {
+    High,
+    Mid,
+    Low,
+}
+
+impl Tier {
+    /// How much a single hit of this tier adds to a capture cycle's risk score
+    pub(crate) fn weight(self) -> i32 {
+        match self {
+            Tier::High => 10,
+            Tier::Mid => 3,
+            Tier::Low => 1,
+        }
+    }
+}
+
+/// Splits OCR text into lowercase words, the same way blacklist entries are split
+pub(crate) fn tokenize(text: &str) -> Vec<String> {
+    text.to_lowercase() // Blacklist is all in lowercase
+        // Remove punctuation. Will help blacklist website URLs in the future
+        .replace(&['(', ')', ',', '\"', '.', ';', ':', '\''][..], " ")
+        .split_whitespace() // Split into words
+        .map(str::to_string)
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct KeywordCount {
+    keyword: String,
+    tier: Tier,
+    count: i32,
+}
+
+/// The blacklisted keywords and phrases, with the number of times each was seen in the current
+/// capture cycle
+#[derive(Debug, Clone)]
+pub(crate) struct Blacklist {
+    /// Indexed by the automaton's pattern ids
+    keywords: Vec<KeywordCount>,
+    /// Matches every keyword at once. Each pattern is a keyword's words joined by single spaces,
+    /// with a space on either side so that only whole words match.
+    automaton: AhoCorasick,
+}
+
+impl Default for Blacklist {
+    fn default() -> Self {
+        Blacklist::from_keywords(Vec::new())
+    }
+}
+
+impl Blacklist {
+    /// Builds the blacklist from the server's keyword lists. A keyword listed under more than one
+    /// tier keeps the most severe one.
+    pub(crate) fn from_json(json: BlacklistJson) -> Self {
+        let mut keywords = Vec::new();
+        for (tier, tier_keywords) in [
+            (Tier::High, json.keywords_high),
+            (Tier::Mid, json.keywords_mid),
+            (Tier::Low, json.keywords_low),
+        ] {
+            keywords.extend(tier_keywords.into_iter().map(|keyword| (keyword, tier)));
+        }
+        Blacklist::from_keywords(keywords)
+    }
+
+    /// Builds the blacklist from keywords, which may each be a single word or a phrase. The first
+    /// occurrence of a duplicate keyword wins.
+    pub(crate) fn from_keywords(keywords: impl IntoIterator<Item = (String, Tier)>) -> Self {
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut counts = Vec::new();
+        let mut patterns = Vec::new();
+        for (keyword, tier) in keywords {
+            let words = tokenize(&keyword);
+            if words.is_empty() {
+                continue;
+            }
+            let pattern = format!(" {} ", words.join(" "));
+            if !seen.insert(pattern.clone()) {
+                continue;
+            }
+            counts.push(KeywordCount {
+                keyword,
+                tier,
+                count: 0,
+            });
+            patterns.push(pattern);
+        }
+
+        Blacklist {
+            keywords: counts,
+            automaton: AhoCorasick::new(patterns).expect("Failed to build blacklist automaton"),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keywords.len()
+    }
+
+    /// Sets every count back to zero, for the start of a new capture cycle
+    pub(crate) fn reset(&mut self) {
+        for keyword in self.keywords.iter_mut() {
+            keyword.count = 0;
+        }
+    }
+
+    /// Counts every blacklisted keyword and phrase in a stream of words, including phrases which
+    /// span line breaks or OCR slices.
+    pub(crate) fn scan(&mut self, words: &[String]) {
+        let mut haystack =
+            String::with_capacity(words.iter().map(|w| w.len() + 1).sum::<usize>() + 1);
+        haystack.push(' ');
+        for word in words {
+            haystack.push_str(word);
+            haystack.push(' ');
+        }
+
+        // Adjacent matches share the space between them, so overlapping matches are needed
+        for found in self.automaton.find_overlapping_iter(&haystack) {
+            self.keywords[found.pattern().as_usize()].count += 1;
+        }
+    }
+
+    /// Number of hits for `keyword` in the current capture cycle
+    pub(crate) fn count(&self, keyword: &str) -> i32 {
+        self.keywords
+            .iter()
+            .find(|k| k.keyword == keyword)
+            .map_or(0, |k| k.count)
+    }
+
+    /// Summarizes the hits of the current capture cycle
+    pub(crate) fn report(&self) -> EventReport {
+        let mut report = EventReport::default();
+        for KeywordCount {
+            keyword,
+            tier,
+            count,
+        } in self.keywords.iter()
+        {
+            if *count > 0 {
+                warn!("{}: count {} ({:?})", keyword, count, tier);
+                report.event.insert(keyword.clone(), *count);
+                *report.tier_counts.get_mut(*tier) += count;
+                report.risk_score += count * tier.weight();
+            }
+        }
+        report
+    }
+}
+
+impl TierCounts {
+    fn get_mut(&mut self, tier: Tier) -> &mut i32 {
+        match tier {
+            Tier::High => &mut self.high,
+            Tier::Mid => &mut self.mid,
+            Tier::Low => &mut self.low,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn blacklist(keywords: &[(&str, Tier)]) -> Blacklist {
+        Blacklist::from_keywords(keywords.iter().map(|(k, t)| (k.to_string(), *t)))
+    }
+
+    #[test]
+    fn test_keeps_most_severe_tier() {
+        let blacklist = Blacklist::from_json(BlacklistJson {
+            keywords_high: vec!["both".to_string()],
+            keywords_mid: vec!["mid".to_string()],
+            keywords_low: vec!["both".to_string(), "low".to_string()],
+        });
+        assert_eq!(blacklist.len(), 3);
+        assert_eq!(blacklist.keywords[0].tier, Tier::High);
+        assert_eq!(blacklist.keywords[2].tier, Tier::Low);
+    }
+
+    #[test]
+    fn test_report_groups_by_tier() {
+        let mut blacklist = blacklist(&[
+            ("high", Tier::High),
+            ("mid", Tier::Mid),
+            ("low", Tier::Low),
+            ("unseen", Tier::Low),
+        ]);
+        blacklist.scan(&tokenize("low low high mid benign"));
+
+        let report = blacklist.report();
+        assert_eq!(report.event.len(), 3);
+        assert_eq!(report.event["low"], 2);
+        assert_eq!(
+            report.tier_counts,
+            TierCounts {
+                high: 1,
+                mid: 1,
+                low: 2
+            }
+        );
+        assert_eq!(report.risk_score, 10 + 3 + 2);
+
+        blacklist.reset();
+        assert_eq!(blacklist.report(), EventReport::default());
+    }
+
+    #[test]
+    fn test_only_whole_words_match() {
+        let mut blacklist = blacklist(&[("cat", Tier::Low)]);
+        blacklist.scan(&tokenize("concatenate cats cat, (cat) CAT"));
+        assert_eq!(blacklist.count("cat"), 3);
+    }
+
+    #[test]
+    fn test_phrases_span_lines_and_slices() {
+        let mut blacklist = blacklist(&[
+            ("testphrase one", Tier::High),
+            ("testphrase", Tier::Low),
+            ("Spaced   Out.Phrase", Tier::Mid),
+        ]);
+
+        // The phrase is broken across a line, and the second phrase across two OCR slices
+        let mut words = tokenize("some text testphrase\none more text spaced");
+        words.extend(tokenize("out phrase and testphrase two"));
+        blacklist.scan(&words);
+
+        assert_eq!(blacklist.count("testphrase one"), 1);
+        assert_eq!(blacklist.count("testphrase"), 2);
+        assert_eq!(blacklist.count("Spaced   Out.Phrase"), 1);
+    }
+
+    #[test]
+    fn test_large_blacklist() {
+        let mut keywords: Vec<(String, Tier)> = (0..50_000)
+            .map(|i| (format!("keyword{} suffix{}", i, i % 7), Tier::Low))
+            .collect();
+        keywords.push(("needle".to_string(), Tier::High));
+        let mut blacklist = Blacklist::from_keywords(keywords);
+        assert_eq!(blacklist.len(), 50_001);
+
+        let text = "filler ".repeat(10_000) + "keyword49999 suffix5 needle keyword3 suffix4";
+        blacklist.scan(&tokenize(&text));
+
+        assert_eq!(blacklist.count("keyword49999 suffix5"), 1);
+        assert_eq!(blacklist.count("keyword3 suffix3"), 0);
+        assert_eq!(blacklist.report().risk_score, 11);
+    }
+}
@@ -0,0 +1,152 @@
+//! A self-test of OCR, so that broken or replaced trained data isn't mistaken for a clean screen.
+//!
+//! An image with known text is embedded in the binary and read like a screenshot, at startup
+//! and then periodically. If too few of its keywords are found, OCR is reported as unhealthy.
+
+use crate::monitoring::blacklist::{Blacklist, Tier};
+use crate::monitoring::{analyze_image, Slicing};
+use crate::requests::Event;
+use crate::scheduler::Scheduler;
+use crate::OpenAccError;
+use image::DynamicImage;
+use leptess::LepTess;
+use rust_embed::RustEmbed;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(RustEmbed)]
+#[folder = "src/monitoring/static/"]
+struct Assets;
+
+const CANARY_IMAGE: &str = "canary.png";
+
+const CANARY_KEYWORD: &str = "testkeyword1";
+
+/// Lines of the keyword in the canary image
+const CANARY_EXPECTED_HITS: i32 = 16;
+
+/// Healthy OCR can miss a few lines, but finding fewer than this means it is broken
+const CANARY_MIN_HITS: i32 = 11;
+
+/// How often the canary is read after the check at startup
+pub(crate) const CANARY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn canary_image() -> Result<DynamicImage, Box<dyn Error>> {
+    let asset = Assets::get(CANARY_IMAGE).ok_or("Canary image is missing")?;
+    Ok(image::load_from_memory(&asset.data)?)
+}
+
+/// The event to report if `found` hits of the keyword means OCR is broken
+fn verdict(found: i32) -> Option<Event> {
+    (found < CANARY_MIN_HITS).then_some(Event::OcrUnhealthy {
+        found,
+        expected: CANARY_EXPECTED_HITS,
+    })
+}
+
+/// Decides when the canary is due, and reads it
+pub(crate) struct OcrCanary {
+    interval: Duration,
+    /// `None` when the canary is never read
+    next_check: Option<Instant>,
+}
+
+impl OcrCanary {
+    /// Reads the canary straight away, then every `interval`
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_check: Some(Instant::now()),
+        }
+    }
+
+    /// Never reads the canary
+    #[cfg(test)]
+    pub(crate) fn disabled() -> Self {
+        Self {
+            interval: Duration::MAX,
+            next_check: None,
+        }
+    }
+
+    /// Reads the canary with the same OCR settings as screenshots, if it is due. Returns the
+    /// event to report if OCR is unhealthy.
+    pub(crate) async fn check_if_due(
+        &mut self,
+        lt: &Arc<Mutex<LepTess>>,
+        slicing: &Slicing,
+        resolution: i32,
+        scheduler: &Scheduler,
+    ) -> Result<Option<Event>, OpenAccError> {
+        let now = Instant::now();
+        if self.next_check.is_none_or(|at| now < at) {
+            return Ok(None);
+        }
+        self.next_check = now.checked_add(self.interval);
+
+        let mut blacklist =
+            Blacklist::from_keywords(vec![(CANARY_KEYWORD.to_string(), Tier::High)]);
+        analyze_image(
+            canary_image()?,
+            lt,
+            &mut blacklist,
+            slicing,
+            resolution,
+            scheduler,
+        )
+        .await?;
+        let found = blacklist.count(CANARY_KEYWORD);
+        info!("OCR canary: found {} of {}", found, CANARY_EXPECTED_HITS);
+        Ok(verdict(found))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::scheduler::FixedInterval;
+    use crate::OCR_SOURCE_RESOLUTION;
+    use pretty_assertions::assert_eq;
+    use tokio_util::sync::CancellationToken;
+
+    #[test]
+    fn test_verdict() {
+        assert_eq!(verdict(CANARY_EXPECTED_HITS), None);
+        assert_eq!(verdict(CANARY_MIN_HITS), None);
+        assert_eq!(
+            verdict(0),
+            Some(Event::OcrUnhealthy {
+                found: 0,
+                expected: CANARY_EXPECTED_HITS
+            })
+        );
+    }
+
+    /// The embedded canary reads as healthy, and is only read again once due
+    #[tokio::test]
+    async fn test_canary_is_healthy() {
+        let lt = Arc::new(Mutex::new(LepTess::new(None, "eng").unwrap()));
+        let scheduler = Scheduler::new(
+            CancellationToken::new(),
+            Box::new(FixedInterval(Duration::ZERO)),
+        );
+        let mut canary = OcrCanary::new(CANARY_INTERVAL);
+
+        let event = canary
+            .check_if_due(&lt, &Slicing::default(), OCR_SOURCE_RESOLUTION, &scheduler)
+            .await
+            .unwrap();
+        assert_eq!(event, None);
+        assert!(canary.next_check.unwrap() > Instant::now() + CANARY_INTERVAL / 2);
+
+        let mut disabled = OcrCanary::disabled();
+        let event = disabled
+            .check_if_due(&lt, &Slicing::default(), OCR_SOURCE_RESOLUTION, &scheduler)
+            .await
+            .unwrap();
+        assert_eq!(event, None);
+        assert!(disabled.next_check.is_none());
+    }
+}
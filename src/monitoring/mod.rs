@@ -1,222 +1,318 @@
-use crate::ImageReader;
+mod blacklist;
+pub(crate) mod canary;
+pub(crate) mod ocr;
 
 pub(crate) async fn monitor(
-    running: Arc<AtomicBool>,
-    mut lt: LepTess,
+    config: &Config,
+    scheduler: &mut Scheduler,
+    lt: LepTess,
     auth: &mut Auth,
+    queue: &EventQueue,
+    capture: &mut dyn CaptureSource,
+    checks: &mut SelfChecks,
 ) -> Result<(), OpenAccError> {
-    let mut blacklist = get_blacklist(auth).await?; // TODO: Handle if no internet connection here
+    // Shared with the blocking threads which run OCR
+    let lt = Arc::new(Mutex::new(lt));
+    let cache_path = &config.blacklist_cache_path;
+    let (mut blacklist, mut blacklist_cached) = load_blacklist(auth, cache_path, scheduler).await?;
+    info!("loaded {} blacklisted keywords", blacklist.len());
+    let slicing = Slicing {
+        height: config.ocr_slice_height,
+        overlap: config.ocr_slice_overlap,
+    };
+    let resolution = config.ocr_source_resolution;
+    let mut blind_detector = BlindDetector::default();
+    let mut input = InputActivity::new();
 
-    while running.load(Ordering::SeqCst) {
+    while !scheduler.is_shutdown() {
         info!("starting loop");
-        rotate_log()?;
+        // Timed from here, so that a suspend or clock change during OCR is caught too
+        let cycle_started = ClockReading::now();
+        rotate_log(&config.log_path, config.log_line_limit)?;
         info!("rotated log");
 
-        // Reset the blacklist values
-        for v in blacklist.values_mut() {
-            *v = 0;
+        // Replace a blacklist loaded from the cache as soon as the server can be reached
+        if blacklist_cached {
+            match get_blacklist(auth, cache_path).await {
+                Ok(fetched) => {
+                    info!("fetched {} blacklisted keywords", fetched.len());
+                    blacklist = fetched;
+                    blacklist_cached = false;
+                }
+                Err(e) => warn!("Still using the cached blacklist: {}", e),
+            }
         }
 
-        let screens = match Screen::all() {
-            Ok(screens) => {
-                warn!("screens: {}", screens.len());
-                screens
-            }
-            Err(e) => {
-                warn!("Screen error");
-                warn!("Error: {}", e);
-                continue;
-            }
-        };
+        // Make sure OCR still works, since a broken engine would make every screen look clean
+        if let Some(event) = checks
+            .canary
+            .check_if_due(&lt, &slicing, resolution, scheduler)
+            .await?
+        {
+            warn!("OCR is unhealthy: {:?}", event);
+            queue.push(&QueuedEvent::new(SystemTime::now(), event))?;
+        }
 
-        for screen in screens {
-            let start = Instant::now();
-            let image = screen.capture().unwrap();
-            let buffer = image.buffer();
+        // Reset the blacklist values
+        blacklist.reset();
+        let captured_at = SystemTime::now();
+
+        match capture.capture().await {
+            Ok(images) => {
+                let blind = blind_detector.classify(&images, input.check());
+                for &(screen, reason) in blind.iter() {
+                    warn!("Screen {} is blind: {:?}", screen, reason);
+                    queue.push(&QueuedEvent::new(
+                        captured_at,
+                        Event::CaptureBlind { screen, reason },
+                    ))?;
+                }
 
-            let img = ImageReader::new(Cursor::new(buffer))
-                .with_guessed_format()?
-                .decode()?;
+                let mut analyzed = 0;
+                for (screen, img) in images.into_iter().enumerate() {
+                    // A blank screen has no text to read
+                    if blind
+                        .iter()
+                        .any(|&(s, reason)| s == screen && !reason.is_readable())
+                    {
+                        continue;
+                    }
+                    let start = Instant::now();
+                    analyze_image(img, &lt, &mut blacklist, &slicing, resolution, scheduler)
+                        .await?;
+                    warn!("elapsed time: {:?}", start.elapsed());
+                    analyzed += 1;
+                }
 
-            analyze_image(img, &mut lt, &mut blacklist, &running)?;
+                // A cycle where no screen could be seen is not reported as clean
+                if analyzed > 0 {
+                    let report = blacklist.report();
+                    info!("risk score: {}", report.risk_score);
 
-            warn!("elapsed time: {:?}", start.elapsed());
-        }
-        let mut event_map = HashMap::new();
-        for (keyword, count) in blacklist.iter() {
-            if *count > 0 {
-                warn!("{}: count {}", keyword, count);
-                event_map.insert(keyword.clone(), *count);
+                    // Queue the event on disk first, so it is not lost if the server can't be
+                    // reached
+                    queue.push(&QueuedEvent::new(captured_at, Event::Keywords(report)))?;
+                }
+            }
+            Err(e) => {
+                warn!("Screen error");
+                warn!("Error: {}", e);
+                queue.push(&QueuedEvent::new(
+                    captured_at,
+                    Event::CaptureBlind {
+                        screen: 0,
+                        reason: BlindReason::CaptureFailed,
+                    },
+                ))?;
             }
         }
 
-        post_event(auth, event_map).await?;
-
-        let sleep_seconds = min(
-            MAX_SLEEP_SECONDS,
-            MIN_SLEEP_SECONDS + rand::random::<u64>() % (MAX_SLEEP_SECONDS - MIN_SLEEP_SECONDS),
+        // Keep the device authenticated while waiting, so that refreshing never delays a capture
+        let (_, waited) = tokio::join!(
+            auth.maintain(),
+            scheduler.wait_for_next_capture(cycle_started)
         );
-
-        // Loop for thread sleep to prevent busy-waiting and allow detection of SIGTERM
-        for _ in 0..sleep_seconds {
-            if !running.load(Ordering::SeqCst) {
-                info!("Exiting due to SIGTERM");
-
-                return Err(OpenAccError::SigTerm);
-            }
-            thread::sleep(time::Duration::from_secs(1));
+        let cycle = waited?;
+        if let Some(gap) = checks.gaps.check(&cycle) {
+            warn!("Captures were interrupted: {:?}", gap);
+            queue.push(&QueuedEvent::new(cycle.started.wall, gap))?;
         }
     }
     Ok(())
 }
 
 use crate::auth::Auth;
-use crate::{
-    OpenAccError, LOG_FILE_LINE_COUNT_LIMIT, LOG_PATH, MAX_SLEEP_SECONDS, MIN_SLEEP_SECONDS,
-};
+use crate::capture::{BlindDetector, BlindReason, CaptureSource, InputActivity};
+use crate::config::Config;
+use crate::monitoring::blacklist::{tokenize, Blacklist};
+use crate::monitoring::canary::OcrCanary;
+use crate::monitoring::ocr::{merge_slice_words, parse_tsv};
+use crate::queue::{EventQueue, QueuedEvent};
+use crate::requests::{BlacklistJson, Event};
+use crate::scheduler::gaps::{ClockReading, GapDetector};
+use crate::scheduler::Scheduler;
+use crate::{OpenAccError, OCR_SLICE_HEIGHT, OCR_SLICE_OVERLAP};
 use image::imageops::crop_imm;
 use image::DynamicImage;
 use leptess::LepTess;
-use screenshots::Screen;
-use serde_json::Value;
-
-use crate::requests::{make_request_with_id_token, EventBodyJson};
 
 use std::cmp::min;
-use std::collections::HashMap;
 use std::error::Error;
+use std::fs;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Cursor};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Instant;
-use std::{fs, thread, time};
-
-fn rotate_log() -> Result<(), Box<dyn Error>> {
-    let lines = BufReader::new(File::open(LOG_PATH)?).lines();
-    if lines.count() > LOG_FILE_LINE_COUNT_LIMIT {
-        let lines_text = BufReader::new(File::open(LOG_PATH)?)
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Delay before fetching the blacklist again when there is no cached copy. Doubles with each
+/// failure.
+const BLACKLIST_MIN_RETRY_DELAY: Duration = Duration::from_secs(5);
+const BLACKLIST_MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 5);
+
+/// Checks that the capture cycle itself is working, run between captures
+pub(crate) struct SelfChecks {
+    pub(crate) gaps: GapDetector,
+    pub(crate) canary: OcrCanary,
+}
+
+fn rotate_log(log_path: &Path, line_limit: usize) -> Result<(), Box<dyn Error>> {
+    if !log_path.exists() {
+        return Ok(());
+    }
+    let lines = BufReader::new(File::open(log_path)?).lines();
+    if lines.count() > line_limit {
+        let lines_text = BufReader::new(File::open(log_path)?)
             .lines()
-            .skip(LOG_FILE_LINE_COUNT_LIMIT / 2)
+            .skip(line_limit / 2)
             .map(|x| x.unwrap())
             .collect::<Vec<String>>()
             .join("\n");
 
-        fs::write(LOG_PATH, lines_text)?;
+        fs::write(log_path, lines_text)?;
     }
     Ok(())
 }
 
-async fn get_blacklist(auth: &mut Auth) -> Result<HashMap<String, i32>, Box<dyn Error>> {
-    let res = auth
-        .client
-        .get("https://us-central1-openaccountability.cloudfunctions.net/getBlacklist")
-        .bearer_auth(auth.device.refresh_token.clone())
-        .send()
-        .await?;
-
-    let json_body: Value = res.json().await?;
-
-    // Flatten the arrays of "keywords_high", "keywords_mid", and "keywords_low" into a vec
-    let mut blacklist_vec: Vec<String> = Vec::new();
-    for key in ["keywords_high", "keywords_mid", "keywords_low"].iter() {
-        let keywords = json_body[key].as_array().unwrap();
-        for keyword in keywords {
-            blacklist_vec.push(keyword.as_str().unwrap().to_string());
+/// Fetches the blacklist from the server, keeping a copy in `cache_path` for starting up offline
+async fn get_blacklist(auth: &mut Auth, cache_path: &Path) -> Result<Blacklist, Box<dyn Error>> {
+    let json_body = auth.api.get_blacklist(&mut auth.device).await?;
+    if let Some(dir) = cache_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(cache_path, serde_json::to_vec(&json_body)?)?;
+
+    Ok(Blacklist::from_json(json_body))
+}
+
+fn read_cached_blacklist(cache_path: &Path) -> Result<Blacklist, Box<dyn Error>> {
+    let json_body: BlacklistJson = serde_json::from_slice(&fs::read(cache_path)?)?;
+    Ok(Blacklist::from_json(json_body))
+}
+
+/// The blacklist from the server, or the copy cached by an earlier run if the server can't be
+/// reached, along with whether it came from the cache. Without a cached copy, retries with
+/// backoff until the server answers.
+async fn load_blacklist(
+    auth: &mut Auth,
+    cache_path: &Path,
+    scheduler: &Scheduler,
+) -> Result<(Blacklist, bool), OpenAccError> {
+    let mut retry_delay = BLACKLIST_MIN_RETRY_DELAY;
+    loop {
+        let e = match get_blacklist(auth, cache_path).await {
+            Ok(blacklist) => return Ok((blacklist, false)),
+            Err(e) => e,
+        };
+        match read_cached_blacklist(cache_path) {
+            Ok(blacklist) => {
+                warn!("Using the cached blacklist, fetching failed: {}", e);
+                return Ok((blacklist, true));
+            }
+            Err(cache_error) => warn!(
+                "Failed to fetch the blacklist, retrying in {:?}: {} (no cached copy: {})",
+                retry_delay, e, cache_error
+            ),
         }
+        scheduler.sleep(retry_delay).await?;
+        retry_delay = min(retry_delay * 2, BLACKLIST_MAX_RETRY_DELAY);
     }
+}
+
+/// How a screenshot is cut into horizontal slices for OCR
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Slicing {
+    /// Height of each slice, in pixels
+    pub(crate) height: u32,
+    /// Number of pixels each slice shares with the one before it. Should be taller than a line
+    /// of text, so that a line on a slice boundary is seen whole by at least one slice.
+    pub(crate) overlap: u32,
+}
 
-    // Turn blacklist vec into a HashMap for faster lookup
-    let mut blacklist: HashMap<String, i32> = HashMap::new();
-    for item in blacklist_vec.iter() {
-        if !blacklist.contains_key(item) {
-            blacklist.insert(item.clone(), 0);
+impl Default for Slicing {
+    fn default() -> Self {
+        Slicing {
+            height: OCR_SLICE_HEIGHT,
+            overlap: OCR_SLICE_OVERLAP,
         }
     }
+}
 
-    Ok(blacklist)
+/// Runs tesseract on one slice of a screenshot, returning its TSV output
+fn read_slice(
+    img: &DynamicImage,
+    lt: &Mutex<LepTess>,
+    top: u32,
+    height: u32,
+    resolution: i32,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let crop = crop_imm(img, 0, top, img.width(), height);
+
+    // Convert to tiff so that tesseract/leptonica can read it on Windows
+    let mut tiff_buffer = Vec::new();
+    crop.to_image().write_to(
+        &mut Cursor::new(&mut tiff_buffer),
+        image::ImageOutputFormat::Tiff,
+    )?;
+
+    let mut lt = lt
+        .lock()
+        .map_err(|_| "OCR engine poisoned by an earlier panic")?;
+    lt.set_image_from_mem(&tiff_buffer).unwrap();
+    lt.set_source_resolution(resolution);
+    Ok(lt.get_tsv_text(0)?)
 }
 
-fn analyze_image(
+async fn analyze_image(
     img: DynamicImage,
-    lt: &mut leptess::LepTess,
-    blacklist: &mut HashMap<String, i32>,
-    running: &Arc<AtomicBool>,
+    lt: &Arc<Mutex<LepTess>>,
+    blacklist: &mut Blacklist,
+    slicing: &Slicing,
+    resolution: i32,
+    scheduler: &Scheduler,
 ) -> Result<(), OpenAccError> {
-    let slice_height = 512;
-    let mut tiff_buffer = Vec::new();
-    for i in (0..img.height()).step_by(slice_height) {
-        info!("slice {} - {}", i, i + slice_height as u32);
+    assert!(slicing.overlap < slicing.height);
+    let step = (slicing.height - slicing.overlap) as usize;
+    let img = Arc::new(img);
+    // Words from every slice, in reading order, so that phrases split across slices still match
+    let mut words = Vec::new();
+    for i in (0..img.height()).step_by(step) {
+        let slice_bottom = min(i + slicing.height, img.height());
+        let is_last_slice = slice_bottom == img.height();
+        info!("slice {} - {}", i, slice_bottom);
         let start_slice = Instant::now();
-        tiff_buffer.clear(); // Remove the data, but keep the allocated memory
-        let crop = crop_imm(&img, 0, i, img.width(), slice_height as u32);
-
-        // Convert to tiff so that tesseract/leptonica can read it on Windows
-        crop.to_image().write_to(
-            &mut Cursor::new(&mut tiff_buffer),
-            image::ImageOutputFormat::Tiff,
-        )?;
-        lt.set_image_from_mem(&tiff_buffer).unwrap();
-        lt.set_source_resolution(100);
-
-        lt.get_utf8_text()?
-            .to_lowercase() // Blacklist is all in lowercase
-            // Remove punctuation. Will help blacklist website URLs in the future
-            .replace(&['(', ')', ',', '\"', '.', ';', ':', '\''][..], " ")
-            .split_whitespace() // Split into words
-            .for_each(|x|
-                // If string is in the blacklist, then increment its count
-                if blacklist.contains_key(x) {
-                    blacklist.insert(x.to_string(), 1 + blacklist[x]);
-
-                    // Get the position of the item using the index_map
-                    // let index = blacklist.get(x).unwrap();
-                    println!("blacklist {} now has count {}", x, blacklist[x]);
-                    // blacklist_counts[*index] += 1;
-                }
-            );
+
+        // OCR is CPU bound, so keep it off the async runtime's threads
+        let (slice_img, slice_lt, height) = (img.clone(), lt.clone(), slicing.height);
+        let tsv = tokio::task::spawn_blocking(move || {
+            read_slice(&slice_img, &slice_lt, i, height, resolution)
+        })
+        .await?
+        .map_err(|e| OpenAccError::OtherError(e))?;
+
+        merge_slice_words(
+            &mut words,
+            parse_tsv(&tsv, i),
+            i,
+            (!is_last_slice).then_some(slice_bottom),
+        );
         let time_elapsed = start_slice.elapsed();
         info!("slice time: {:?}", start_slice.elapsed());
 
-        let sleep_seconds = time_elapsed.as_secs() * 10;
+        // Keep OCR to a fraction of one CPU
+        let throttle = Duration::from_secs(time_elapsed.as_secs() * 10);
+        if !throttle.is_zero() {
+            scheduler.sleep(throttle).await?;
+        }
 
-        for _ in 0..sleep_seconds {
-            if !running.load(Ordering::SeqCst) {
-                info!("Exiting due to SIGTERM");
-                return Err(OpenAccError::SigTerm);
-            }
-            thread::sleep(time::Duration::from_secs(1));
+        if is_last_slice {
+            break;
         }
     }
-    Ok(())
-}
 
-async fn post_event(
-    auth: &mut Auth,
-    event_map: HashMap<String, i32>,
-) -> Result<(), Box<dyn Error>> {
-    info!("about to post");
-    let mut request_json = EventBodyJson {
-        id_token: auth.device.id_token.clone(),
-        device_uuid: auth.device.uuid.clone(),
-        event: event_map,
-    };
-    let request_builder = auth
-        .client
-        .post("https://us-central1-openaccountability.cloudfunctions.net/api/event")
-        .bearer_auth(auth.device.refresh_token.clone())
-        .json(&request_json);
-    let res = make_request_with_id_token(
-        &auth.fire_auth,
-        &mut auth.device,
-        request_builder,
-        &mut request_json,
-    )
-    .await?;
-
-    let response_text = res.text().await?;
-    info!("response: {}", response_text);
-    info!("done posting");
+    // If a blacklisted keyword or phrase is in the text, then increment its count
+    let tokens: Vec<String> = words.iter().flat_map(|word| tokenize(&word.text)).collect();
+    blacklist.scan(&tokens);
     Ok(())
 }
 
@@ -224,56 +320,139 @@ async fn post_event(
 mod tests {
     use super::*;
 
-    use crate::auth::register::Device;
+    use crate::auth::register::MockSignInFlow;
+    use crate::capture::{ReplayCapture, SyntheticCapture};
+    use crate::monitoring::blacklist::Tier;
+    use crate::requests::api::MockAccountabilityApi;
+    use crate::scheduler::FixedInterval;
+    use crate::test_util::{temp_dir, test_device};
+    use crate::{ImageReader, OCR_SOURCE_RESOLUTION};
     use pretty_assertions::assert_eq;
-    use reqwest::Client;
-    use rocket::futures::TryFutureExt;
-
-    fn create_device_info_file_from_env() {
-        let device = Device {
-            refresh_token: dotenv!("TEST_REFRESH_TOKEN").to_string(),
-            id_token: "".to_string(),
-            uuid: dotenv!("TEST_DEVICE_UID").to_string(),
-            name: "".to_string(),
-            safe_shutdown_id: "".to_string(),
-        };
-        device.write_device_info(false).unwrap();
+    use tokio_util::sync::CancellationToken;
+
+    fn test_scheduler(shutdown: CancellationToken) -> Scheduler {
+        Scheduler::new(shutdown, Box::new(FixedInterval(Duration::ZERO)))
     }
 
-    #[tokio::test]
-    async fn test_post_event() {
-        create_device_info_file_from_env();
+    fn test_ocr() -> Arc<Mutex<LepTess>> {
+        Arc::new(Mutex::new(LepTess::new(None, "eng").unwrap()))
+    }
 
-        let client = Client::new();
-        let mut auth = Auth::new(client).await.unwrap();
-        assert!(!auth.device.safe_shutdown_id.is_empty());
-        assert_ne!(auth.device.uuid, dotenv!("TEST_DEVICE_UID").to_string());
-        assert_eq!(
-            auth.device.refresh_token,
-            dotenv!("TEST_REFRESH_TOKEN").to_string()
-        );
+    fn no_checks() -> SelfChecks {
+        SelfChecks {
+            gaps: GapDetector::default(),
+            canary: OcrCanary::disabled(),
+        }
+    }
 
-        let mut event_map = HashMap::new();
-        event_map.insert("testkeyword1".to_string(), 5);
+    /// The default config, with the blacklist cached under `dir`
+    fn test_config(dir: &Path) -> Config {
+        Config {
+            blacklist_cache_path: dir.join("blacklist.json"),
+            ..Config::default()
+        }
+    }
 
-        post_event(&mut auth, event_map).await.unwrap();
+    fn test_auth(api: MockAccountabilityApi) -> Auth {
+        Auth::registered(
+            Arc::new(api),
+            test_device(),
+            Arc::new(MockSignInFlow::new()),
+        )
     }
 
     /// Get the blacklist from the server
     #[tokio::test]
     async fn test_get_blacklist() {
-        create_device_info_file_from_env();
+        let mut api = MockAccountabilityApi::new();
+        api.expect_get_blacklist().returning(|_| {
+            Ok(BlacklistJson {
+                keywords_high: vec!["high1".to_string(), "high2".to_string()],
+                keywords_mid: vec!["mid1".to_string(), "high1".to_string()],
+                keywords_low: vec!["low1".to_string()],
+            })
+        });
+        let mut auth = test_auth(api);
+        let dir = temp_dir();
+        let cache_path = dir.path().join("blacklist.json");
+
+        let blacklist = get_blacklist(&mut auth, &cache_path).await.unwrap();
+        assert_eq!(blacklist.len(), 4);
+        assert_eq!(blacklist.report().risk_score, 0);
+        assert_eq!(read_cached_blacklist(&cache_path).unwrap().len(), 4);
+    }
+
+    /// Without a connection, the blacklist from the last run is used, and without one of those
+    /// the server is asked again until it answers
+    #[tokio::test]
+    async fn test_load_blacklist_offline() {
+        let dir = temp_dir();
+        let cache_path = dir.path().join("blacklist.json");
+        let scheduler = test_scheduler(CancellationToken::new());
+
+        let mut api = MockAccountabilityApi::new();
+        let mut seq = mockall::Sequence::new();
+        api.expect_get_blacklist()
+            .times(2)
+            .in_sequence(&mut seq)
+            .returning(|_| Err("no internet connection".into()));
+        api.expect_get_blacklist()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| {
+                Ok(BlacklistJson {
+                    keywords_high: vec!["high1".to_string()],
+                    ..BlacklistJson::default()
+                })
+            });
+        api.expect_get_blacklist()
+            .returning(|_| Err("no internet connection".into()));
+        let mut auth = test_auth(api);
+
+        tokio::time::pause();
+        let (blacklist, cached) = load_blacklist(&mut auth, &cache_path, &scheduler)
+            .await
+            .unwrap();
+        assert_eq!((blacklist.len(), cached), (1, false));
+
+        let (blacklist, cached) = load_blacklist(&mut auth, &cache_path, &scheduler)
+            .await
+            .unwrap();
+        assert_eq!((blacklist.len(), cached), (1, true));
+    }
 
-        let client = Client::new();
-        let mut auth = Auth::new(client).await.unwrap();
-        let blacklist = get_blacklist(&mut auth).await.unwrap();
-        assert!(blacklist.len() > 20);
+    /// The monitor fetches the blacklist and exits cleanly when already stopped
+    #[tokio::test]
+    async fn test_monitor_stopped() {
+        let mut api = MockAccountabilityApi::new();
+        api.expect_get_blacklist()
+            .times(1)
+            .returning(|_| Ok(BlacklistJson::default()));
+        let mut auth = test_auth(api);
+
+        let dir = temp_dir();
+        let queue = EventQueue::open(dir.path().join("events")).unwrap();
+        let lt = leptess::LepTess::new(None, "eng").unwrap();
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+        let mut capture = SyntheticCapture::new(16, 16);
+        monitor(
+            &test_config(dir.path()),
+            &mut test_scheduler(shutdown),
+            lt,
+            &mut auth,
+            &queue,
+            &mut capture,
+            &mut no_checks(),
+        )
+        .await
+        .unwrap();
     }
 
     #[tokio::test]
     async fn test_analyze_images() {
-        let mut lt = leptess::LepTess::new(None, "eng").unwrap();
-        let running = Arc::new(AtomicBool::new(true));
+        let lt = test_ocr();
+        let scheduler = test_scheduler(CancellationToken::new());
 
         {
             let img = ImageReader::open("tests/test_image.png")
@@ -283,12 +462,21 @@ mod tests {
                 .decode()
                 .unwrap();
 
-            let mut blacklist: HashMap<String, i32> = HashMap::new();
-            blacklist.insert("testkeyword1".to_string(), 0);
-
-            analyze_image(img, &mut lt, &mut blacklist, &running).unwrap();
+            let mut blacklist =
+                Blacklist::from_keywords(vec![("testkeyword1".to_string(), Tier::High)]);
+
+            analyze_image(
+                img,
+                &lt,
+                &mut blacklist,
+                &Slicing::default(),
+                OCR_SOURCE_RESOLUTION,
+                &scheduler,
+            )
+            .await
+            .unwrap();
             // Check that we detected a reasonable number of the test keyword
-            assert!(blacklist["testkeyword1"] > 10);
+            assert!(blacklist.count("testkeyword1") > 10);
         }
 
         {
@@ -299,14 +487,180 @@ mod tests {
                 .decode()
                 .unwrap();
 
-            let mut blacklist: HashMap<String, i32> = HashMap::new();
-            blacklist.insert("testkeyword1".to_string(), 0);
-
-            analyze_image(img, &mut lt, &mut blacklist, &running).unwrap();
+            let mut blacklist =
+                Blacklist::from_keywords(vec![("testkeyword1".to_string(), Tier::High)]);
+
+            analyze_image(
+                img,
+                &lt,
+                &mut blacklist,
+                &Slicing::default(),
+                OCR_SOURCE_RESOLUTION,
+                &scheduler,
+            )
+            .await
+            .unwrap();
             // Check that we detected a reasonable number of the test keyword
-            eprintln!("count: {}", blacklist["testkeyword1"]);
-            assert!(blacklist["testkeyword1"] > 7);
+            eprintln!("count: {}", blacklist.count("testkeyword1"));
+            assert!(blacklist.count("testkeyword1") > 7);
             // TODO: Figure out how to improve performance on higher-res images
         }
     }
+
+    /// Text straddling a slice boundary is read whole by the overlapping slice, and text inside
+    /// the overlap is only counted once
+    #[tokio::test]
+    async fn test_analyze_image_slice_boundary() {
+        let lt = test_ocr();
+        let scheduler = test_scheduler(CancellationToken::new());
+
+        // One line of "testkeyword1" from the test image, about 20 pixels tall
+        let source = ImageReader::open("tests/test_image.png")
+            .unwrap()
+            .decode()
+            .unwrap()
+            .to_rgba8();
+        let line = crop_imm(&source, 0, 0, source.width(), 19).to_image();
+        let background = *source.get_pixel(source.width() - 1, source.height() - 1);
+
+        let slicing = Slicing {
+            height: 512,
+            overlap: 64,
+        };
+        let mut canvas = image::RgbaImage::from_pixel(source.width(), 1024, background);
+        // Cut in half by the end of the first slice
+        image::imageops::replace(&mut canvas, &line, 0, 502);
+        // Entirely inside the region shared by the first and second slices
+        image::imageops::replace(&mut canvas, &line, 0, 460);
+
+        let mut blacklist =
+            Blacklist::from_keywords(vec![("testkeyword1".to_string(), Tier::High)]);
+        analyze_image(
+            DynamicImage::ImageRgba8(canvas),
+            &lt,
+            &mut blacklist,
+            &slicing,
+            OCR_SOURCE_RESOLUTION,
+            &scheduler,
+        )
+        .await
+        .unwrap();
+        assert_eq!(blacklist.count("testkeyword1"), 2);
+    }
+
+    /// Replays a screen image through one full monitoring cycle
+    #[tokio::test]
+    async fn test_monitor_replay() {
+        /// Replays a directory, then asks the monitor to stop after the first cycle
+        struct ReplayOnce {
+            replay: ReplayCapture,
+            shutdown: CancellationToken,
+        }
+
+        #[async_trait::async_trait]
+        impl CaptureSource for ReplayOnce {
+            async fn capture(&mut self) -> Result<Vec<DynamicImage>, Box<dyn Error>> {
+                self.shutdown.cancel();
+                self.replay.capture().await
+            }
+        }
+
+        let mut api = MockAccountabilityApi::new();
+        api.expect_get_blacklist().returning(|_| {
+            Ok(BlacklistJson {
+                keywords_high: vec!["testkeyword1".to_string()],
+                keywords_mid: vec![],
+                keywords_low: vec![],
+            })
+        });
+        let mut auth = test_auth(api);
+
+        let dir = temp_dir();
+        let queue = EventQueue::open(dir.path().join("events")).unwrap();
+        let lt = leptess::LepTess::new(None, "eng").unwrap();
+        let shutdown = CancellationToken::new();
+        let mut capture = ReplayOnce {
+            replay: ReplayCapture::new(Path::new("tests")).unwrap(),
+            shutdown: shutdown.clone(),
+        };
+
+        let result = monitor(
+            &test_config(dir.path()),
+            &mut test_scheduler(shutdown),
+            lt,
+            &mut auth,
+            &queue,
+            &mut capture,
+            &mut no_checks(),
+        )
+        .await;
+        assert!(matches!(result, Err(OpenAccError::SigTerm)));
+
+        let pending = queue.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        let event: QueuedEvent =
+            serde_json::from_str(&fs::read_to_string(&pending[0]).unwrap()).unwrap();
+        let Event::Keywords(report) = event.event else {
+            panic!("expected a keyword report, got {:?}", event.event);
+        };
+        assert!(report.event["testkeyword1"] > 10);
+        assert_eq!(
+            report.risk_score,
+            report.event["testkeyword1"] * Tier::High.weight()
+        );
+    }
+
+    /// A blank screen is reported as a blind capture instead of a clean cycle
+    #[tokio::test]
+    async fn test_monitor_blank_screen() {
+        /// Returns one black frame, then asks the monitor to stop
+        struct BlankOnce {
+            shutdown: CancellationToken,
+        }
+
+        #[async_trait::async_trait]
+        impl CaptureSource for BlankOnce {
+            async fn capture(&mut self) -> Result<Vec<DynamicImage>, Box<dyn Error>> {
+                self.shutdown.cancel();
+                Ok(vec![DynamicImage::new_rgb8(1920, 1080)])
+            }
+        }
+
+        let mut api = MockAccountabilityApi::new();
+        api.expect_get_blacklist()
+            .returning(|_| Ok(BlacklistJson::default()));
+        let mut auth = test_auth(api);
+
+        let dir = temp_dir();
+        let queue = EventQueue::open(dir.path().join("events")).unwrap();
+        let lt = leptess::LepTess::new(None, "eng").unwrap();
+        let shutdown = CancellationToken::new();
+        let mut capture = BlankOnce {
+            shutdown: shutdown.clone(),
+        };
+
+        let result = monitor(
+            &test_config(dir.path()),
+            &mut test_scheduler(shutdown),
+            lt,
+            &mut auth,
+            &queue,
+            &mut capture,
+            &mut no_checks(),
+        )
+        .await;
+        assert!(matches!(result, Err(OpenAccError::SigTerm)));
+
+        let pending = queue.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        let event: QueuedEvent =
+            serde_json::from_str(&fs::read_to_string(&pending[0]).unwrap()).unwrap();
+        assert_eq!(
+            event.event,
+            Event::CaptureBlind {
+                screen: 0,
+                reason: BlindReason::Uniform
+            }
+        );
+    }
 }
@@ -0,0 +1,230 @@
+//! Words recognized by tesseract, with their positions in the full screenshot.
+
+use std::path::PathBuf;
+
+/// Tesseract's page iterator level for individual words, as reported in its TSV output
+const TSV_WORD_LEVEL: &str = "5";
+
+/// Words whose box comes within this many pixels of a slice's cut edge are treated as clipped
+const EDGE_MARGIN: u32 = 2;
+
+/// Two boxes with the same text and at least this much intersection-over-union are the same word
+const DUPLICATE_IOU: f32 = 0.5;
+
+/// Where distributions install tesseract's trained data, searched when `TESSDATA_PREFIX` isn't set
+const TESSDATA_DIRS: [&str; 5] = [
+    "/usr/share/tesseract-ocr/5/tessdata",
+    "/usr/share/tesseract-ocr/4.00/tessdata",
+    "/usr/share/tessdata",
+    "/usr/local/share/tessdata",
+    "/usr/local/share/tesseract-ocr/tessdata",
+];
+
+/// The trained data tesseract loads for `lang` when it is given no data path
+pub(crate) fn traineddata_path(lang: &str) -> Option<PathBuf> {
+    let file = format!("{}.traineddata", lang);
+    std::env::var_os("TESSDATA_PREFIX")
+        .map(PathBuf::from)
+        .into_iter()
+        .chain(TESSDATA_DIRS.iter().map(PathBuf::from))
+        .map(|dir| dir.join(&file))
+        .find(|path| path.exists())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct OcrWord {
+    pub(crate) text: String,
+    pub(crate) left: u32,
+    pub(crate) top: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+impl OcrWord {
+    fn bottom(&self) -> u32 {
+        self.top + self.height
+    }
+
+    fn right(&self) -> u32 {
+        self.left + self.width
+    }
+
+    fn intersection_over_union(&self, other: &OcrWord) -> f32 {
+        let overlap_width =
+            self.right().min(other.right()) as i64 - self.left.max(other.left) as i64;
+        let overlap_height =
+            self.bottom().min(other.bottom()) as i64 - self.top.max(other.top) as i64;
+        if overlap_width <= 0 || overlap_height <= 0 {
+            return 0.0;
+        }
+        let intersection = (overlap_width * overlap_height) as f32;
+        let union = (self.width * self.height + other.width * other.height) as f32 - intersection;
+        intersection / union
+    }
+}
+
+/// Parses the words out of tesseract's TSV output for one slice, offsetting their boxes by the
+/// slice's position in the screenshot.
+pub(crate) fn parse_tsv(tsv: &str, slice_top: u32) -> Vec<OcrWord> {
+    tsv.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 12 || fields[0] != TSV_WORD_LEVEL {
+                return None;
+            }
+            let text = fields[11].trim();
+            if text.is_empty() {
+                return None;
+            }
+            Some(OcrWord {
+                text: text.to_string(),
+                left: fields[6].parse().ok()?,
+                top: fields[7].parse::<u32>().ok()? + slice_top,
+                width: fields[8].parse().ok()?,
+                height: fields[9].parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Adds the words of one slice to the words found so far.
+///
+/// Slices overlap, so a word may be seen twice: once near the bottom of one slice and again near
+/// the top of the next. Such duplicates are dropped. Words cut off by the top edge of a slice, or
+/// by its bottom edge (`slice_bottom`, or `None` for the last slice), are dropped too, since the
+/// neighbouring slice sees them whole.
+pub(crate) fn merge_slice_words(
+    words: &mut Vec<OcrWord>,
+    slice_words: Vec<OcrWord>,
+    slice_top: u32,
+    slice_bottom: Option<u32>,
+) {
+    let previous_len = words.len();
+    for word in slice_words {
+        if slice_top > 0 && word.top <= slice_top + EDGE_MARGIN {
+            continue;
+        }
+        if let Some(bottom) = slice_bottom {
+            if word.bottom() + EDGE_MARGIN >= bottom {
+                continue;
+            }
+        }
+        // Only words from earlier slices which reach into this slice can be duplicates
+        let duplicate = words[..previous_len]
+            .iter()
+            .filter(|seen| seen.bottom() > slice_top)
+            .any(|seen| {
+                seen.text == word.text && seen.intersection_over_union(&word) >= DUPLICATE_IOU
+            });
+        if !duplicate {
+            words.push(word);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn word(text: &str, left: u32, top: u32) -> OcrWord {
+        OcrWord {
+            text: text.to_string(),
+            left,
+            top,
+            width: 100,
+            height: 20,
+        }
+    }
+
+    #[test]
+    fn test_parse_tsv() {
+        let tsv = "1\t1\t0\t0\t0\t0\t0\t0\t133\t64\t-1\t\n\
+                   4\t1\t1\t1\t1\t0\t4\t5\t120\t14\t-1\t\n\
+                   5\t1\t1\t1\t1\t1\t4\t5\t120\t14\t91.5\ttestkeyword1\n\
+                   5\t1\t1\t1\t2\t1\t4\t25\t60\t14\t95.0\tother\n\
+                   5\t1\t1\t1\t2\t2\t70\t25\t5\t14\t10.0\t \n";
+        let words = parse_tsv(tsv, 100);
+        assert_eq!(
+            words,
+            vec![
+                OcrWord {
+                    text: "testkeyword1".to_string(),
+                    left: 4,
+                    top: 105,
+                    width: 120,
+                    height: 14
+                },
+                OcrWord {
+                    text: "other".to_string(),
+                    left: 4,
+                    top: 125,
+                    width: 60,
+                    height: 14
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_words_in_overlap_counted_once() {
+        let mut words = Vec::new();
+        // First slice covers 0..512, second covers 448..960
+        merge_slice_words(
+            &mut words,
+            vec![word("first", 0, 10), word("overlap", 0, 460)],
+            0,
+            Some(512),
+        );
+        merge_slice_words(
+            &mut words,
+            vec![word("overlap", 1, 461), word("second", 0, 600)],
+            448,
+            None,
+        );
+
+        let texts: Vec<&str> = words.iter().map(|w| w.text.as_str()).collect();
+        assert_eq!(texts, vec!["first", "overlap", "second"]);
+    }
+
+    #[test]
+    fn test_clipped_words_left_to_next_slice() {
+        let mut words = Vec::new();
+        // The word straddles the first slice's bottom edge, so OCR only sees part of it
+        merge_slice_words(&mut words, vec![word("testkeyw", 0, 500)], 0, Some(512));
+        assert!(words.is_empty());
+
+        // A word cut by the second slice's top edge was already seen whole by the first
+        merge_slice_words(
+            &mut words,
+            vec![word("yword1", 0, 448), word("testkeyword1", 0, 500)],
+            448,
+            None,
+        );
+        assert_eq!(words.len(), 1);
+        assert_eq!(words[0].text, "testkeyword1");
+    }
+
+    #[test]
+    fn test_repeated_words_on_separate_lines_kept() {
+        let mut words = Vec::new();
+        merge_slice_words(
+            &mut words,
+            vec![word("same", 0, 450), word("same", 0, 475)],
+            0,
+            Some(512),
+        );
+        merge_slice_words(
+            &mut words,
+            vec![
+                word("same", 0, 450),
+                word("same", 0, 475),
+                word("same", 0, 500),
+            ],
+            448,
+            None,
+        );
+        assert_eq!(words.len(), 3);
+    }
+}
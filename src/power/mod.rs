@@ -0,0 +1,338 @@
+//! Shutdown and suspend handling through systemd-logind.
+//!
+//! The daemon holds a delay inhibitor lock, so logind waits for it before the machine shuts down
+//! or suspends. When logind announces either with `PrepareForShutdown` or `PrepareForSleep`, the
+//! daemon gets ready and then releases the lock to let it go ahead. The lock is taken again on
+//! resume, or when a shutdown is cancelled.
+
+use crate::auth::register::Device;
+use crate::queue::EventQueue;
+use crate::requests::api::AccountabilityApi;
+use crate::SERVICE_NAME;
+use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use zbus::zvariant::OwnedFd;
+use zbus::{dbus_proxy, Connection};
+
+const INHIBIT_WHAT: &str = "shutdown:sleep";
+const INHIBIT_WHY: &str = "Saving state and uploading events";
+
+/// Queued events are uploaded for at most this long before a shutdown or suspend, since logind
+/// only waits for delay locks for `InhibitDelayMaxSec` (5 seconds by default)
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(4);
+
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait Manager {
+    fn inhibit(&self, what: &str, who: &str, why: &str, mode: &str) -> zbus::Result<OwnedFd>;
+
+    #[dbus_proxy(signal)]
+    fn prepare_for_shutdown(&self, start: bool) -> zbus::Result<()>;
+
+    #[dbus_proxy(signal)]
+    fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PowerEvent {
+    /// The machine is about to shut down or reboot
+    Shutdown,
+    ShutdownCancelled,
+    /// The machine is about to suspend or hibernate
+    Sleep,
+    Resume,
+}
+
+/// Gets the daemon ready for changes in the machine's power state
+#[async_trait]
+pub(crate) trait PowerHandler: Send {
+    /// Called for each power event. A shutdown or suspend waits until this returns.
+    async fn handle(&mut self, event: PowerEvent);
+}
+
+/// When the machine went to sleep through logind. Clones share the same log.
+#[derive(Clone, Default)]
+pub(crate) struct SleepLog(Arc<Mutex<Vec<SystemTime>>>);
+
+impl SleepLog {
+    pub(crate) fn record(&self, at: SystemTime) {
+        self.0.lock().unwrap().push(at);
+    }
+
+    /// Whether the machine went to sleep between `from` and `to`. Sleeps before `to` are
+    /// forgotten, since the next check starts from there.
+    pub(crate) fn take_between(&self, from: SystemTime, to: SystemTime) -> bool {
+        let mut sleeps = self.0.lock().unwrap();
+        let found = sleeps.iter().any(|&at| from <= at && at <= to);
+        sleeps.retain(|&at| at > to);
+        found
+    }
+}
+
+/// Watches logind for shutdowns and suspends, holding a delay lock while the daemon is running
+pub(crate) struct PowerMonitor {
+    manager: ManagerProxy<'static>,
+    events: BoxStream<'static, zbus::Result<PowerEvent>>,
+    /// Closing this releases the lock
+    inhibitor: Option<OwnedFd>,
+    shutdown_in_progress: Arc<AtomicBool>,
+    sleeps: SleepLog,
+}
+
+impl PowerMonitor {
+    /// Subscribes to logind's signals on `connection`, usually the system bus, and takes the lock
+    pub(crate) async fn new(connection: &Connection) -> zbus::Result<Self> {
+        let manager = ManagerProxy::new(connection).await?;
+        let shutdowns = manager.receive_prepare_for_shutdown().await?.map(|signal| {
+            Ok(match signal.args()?.start {
+                true => PowerEvent::Shutdown,
+                false => PowerEvent::ShutdownCancelled,
+            })
+        });
+        let sleeps = manager.receive_prepare_for_sleep().await?.map(|signal| {
+            Ok(match signal.args()?.start {
+                true => PowerEvent::Sleep,
+                false => PowerEvent::Resume,
+            })
+        });
+        let mut monitor = Self {
+            manager,
+            events: stream::select(shutdowns, sleeps).boxed(),
+            inhibitor: None,
+            shutdown_in_progress: Arc::default(),
+            sleeps: SleepLog::default(),
+        };
+        monitor.inhibit().await?;
+        Ok(monitor)
+    }
+
+    /// Set from when logind announces a shutdown until it is cancelled
+    pub(crate) fn shutdown_in_progress(&self) -> Arc<AtomicBool> {
+        self.shutdown_in_progress.clone()
+    }
+
+    /// Records each time logind puts the machine to sleep
+    pub(crate) fn sleeps(&self) -> SleepLog {
+        self.sleeps.clone()
+    }
+
+    async fn inhibit(&mut self) -> zbus::Result<()> {
+        if self.inhibitor.is_none() {
+            self.inhibitor = Some(
+                self.manager
+                    .inhibit(INHIBIT_WHAT, SERVICE_NAME, INHIBIT_WHY, "delay")
+                    .await?,
+            );
+        }
+        Ok(())
+    }
+
+    /// Passes power events to `handler` for as long as the program runs
+    pub(crate) async fn run(mut self, mut handler: impl PowerHandler) -> zbus::Result<()> {
+        while let Some(event) = self.events.next().await {
+            let event = event?;
+            info!("Power event: {:?}", event);
+            match event {
+                PowerEvent::Shutdown => self.shutdown_in_progress.store(true, Ordering::SeqCst),
+                PowerEvent::ShutdownCancelled => {
+                    self.shutdown_in_progress.store(false, Ordering::SeqCst)
+                }
+                PowerEvent::Sleep => self.sleeps.record(SystemTime::now()),
+                PowerEvent::Resume => {}
+            }
+
+            handler.handle(event).await;
+
+            match event {
+                PowerEvent::Shutdown | PowerEvent::Sleep => self.inhibitor = None,
+                PowerEvent::ShutdownCancelled | PowerEvent::Resume => {
+                    if let Err(e) = self.inhibit().await {
+                        warn!("Failed to take the logind inhibitor lock again: {}", e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stores the safe exit id before a shutdown, so the next run knows the daemon wasn't killed, and
+/// uploads queued events before a shutdown or suspend while the network is still up
+pub(crate) struct PrepareForPowerChange {
+    pub(crate) queue: EventQueue,
+    pub(crate) api: Arc<dyn AccountabilityApi>,
+    pub(crate) credentials: watch::Receiver<Device>,
+    pub(crate) device_path: PathBuf,
+}
+
+impl PrepareForPowerChange {
+    async fn flush_queue(&self) {
+        let mut device = self.credentials.borrow().clone();
+        match tokio::time::timeout(
+            FLUSH_TIMEOUT,
+            self.queue.drain(self.api.as_ref(), &mut device),
+        )
+        .await
+        {
+            Ok(Ok(uploaded)) => info!("Uploaded {} queued events", uploaded),
+            Ok(Err(e)) => warn!("Failed to upload queued events: {}", e),
+            Err(_) => warn!("Gave up uploading queued events after {:?}", FLUSH_TIMEOUT),
+        }
+    }
+}
+
+#[async_trait]
+impl PowerHandler for PrepareForPowerChange {
+    async fn handle(&mut self, event: PowerEvent) {
+        match event {
+            PowerEvent::Shutdown => {
+                let device = self.credentials.borrow().clone();
+                if let Err(e) = device.write_device_info(&self.device_path, true) {
+                    error!("Failed to store the safe exit id: {}", e);
+                }
+                self.flush_queue().await;
+            }
+            PowerEvent::Sleep => self.flush_queue().await,
+            PowerEvent::ShutdownCancelled | PowerEvent::Resume => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_bus::TestBus;
+    use pretty_assertions::assert_eq;
+    use std::io::{ErrorKind, Read};
+    use std::os::fd::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+    use tokio::sync::mpsc;
+    use zbus::{dbus_interface, ConnectionBuilder};
+
+    const LOGIND_PATH: &str = "/org/freedesktop/login1";
+
+    /// Stands in for logind: hands out one end of a socket pair for each inhibitor lock, and
+    /// keeps the other end to tell whether the lock is still held
+    struct MockLogind {
+        inhibits: Arc<Mutex<Vec<(String, String)>>>,
+        locks: Arc<Mutex<Vec<UnixStream>>>,
+    }
+
+    #[dbus_interface(name = "org.freedesktop.login1.Manager")]
+    impl MockLogind {
+        fn inhibit(&self, what: &str, _who: &str, _why: &str, mode: &str) -> OwnedFd {
+            self.inhibits
+                .lock()
+                .unwrap()
+                .push((what.to_string(), mode.to_string()));
+            let (lock, held) = UnixStream::pair().unwrap();
+            held.set_nonblocking(true).unwrap();
+            self.locks.lock().unwrap().push(held);
+            // Safety: the fd was just taken out of the stream, so nothing else owns it
+            unsafe { OwnedFd::from_raw_fd(lock.into_raw_fd()) }
+        }
+    }
+
+    /// Whether the lock handed out by the mock is still held by the daemon
+    fn is_held(lock: &UnixStream) -> bool {
+        let mut buf = [0];
+        matches!((&*lock).read(&mut buf), Err(e) if e.kind() == ErrorKind::WouldBlock)
+    }
+
+    /// Records each event, and whether the lock was held while it was handled
+    struct Recorder {
+        locks: Arc<Mutex<Vec<UnixStream>>>,
+        events: mpsc::UnboundedSender<(PowerEvent, bool)>,
+    }
+
+    #[async_trait]
+    impl PowerHandler for Recorder {
+        async fn handle(&mut self, event: PowerEvent) {
+            let held = self.locks.lock().unwrap().iter().any(is_held);
+            self.events.send((event, held)).unwrap();
+        }
+    }
+
+    async fn emit(logind: &Connection, signal: &str, start: bool) {
+        logind
+            .emit_signal(
+                None::<&str>,
+                LOGIND_PATH,
+                "org.freedesktop.login1.Manager",
+                signal,
+                &(start,),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lock_is_released_after_handling() {
+        let bus = TestBus::start();
+        let inhibits = Arc::new(Mutex::new(Vec::new()));
+        let locks = Arc::new(Mutex::new(Vec::new()));
+        let logind = ConnectionBuilder::address(bus.address.as_str())
+            .unwrap()
+            .name("org.freedesktop.login1")
+            .unwrap()
+            .serve_at(
+                LOGIND_PATH,
+                MockLogind {
+                    inhibits: inhibits.clone(),
+                    locks: locks.clone(),
+                },
+            )
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let monitor = PowerMonitor::new(&bus.connect().await).await.unwrap();
+        let shutdown_in_progress = monitor.shutdown_in_progress();
+        assert!(is_held(&locks.lock().unwrap()[0]));
+        let (tx, mut events) = mpsc::unbounded_channel();
+        tokio::spawn(monitor.run(Recorder {
+            locks: locks.clone(),
+            events: tx,
+        }));
+
+        emit(&logind, "PrepareForSleep", true).await;
+        assert_eq!(events.recv().await.unwrap(), (PowerEvent::Sleep, true));
+        emit(&logind, "PrepareForSleep", false).await;
+        assert_eq!(events.recv().await.unwrap(), (PowerEvent::Resume, false));
+        assert!(!shutdown_in_progress.load(Ordering::SeqCst));
+
+        // The lock is taken again after resuming
+        emit(&logind, "PrepareForShutdown", true).await;
+        assert_eq!(events.recv().await.unwrap(), (PowerEvent::Shutdown, true));
+        assert!(shutdown_in_progress.load(Ordering::SeqCst));
+        emit(&logind, "PrepareForShutdown", false).await;
+        assert_eq!(
+            events.recv().await.unwrap(),
+            (PowerEvent::ShutdownCancelled, false)
+        );
+        assert!(!shutdown_in_progress.load(Ordering::SeqCst));
+
+        // The lock is taken again once the cancelled shutdown has been handled
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while inhibits.lock().unwrap().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let inhibits = inhibits.lock().unwrap();
+        assert!(inhibits
+            .iter()
+            .all(|(what, mode)| what == INHIBIT_WHAT && mode == "delay"));
+    }
+}
@@ -0,0 +1,395 @@
+//! A durable on-disk queue of events waiting to be posted to the server.
+//!
+//! Each event is written to its own file before anything is sent over the network, so events
+//! captured while offline (or before a crash) are uploaded the next time the server is reachable.
+//! Events the server rejects outright are moved to a `rejected` subdirectory instead of blocking
+//! the ones behind them.
+
+use crate::auth::register::Device;
+use crate::requests::api::AccountabilityApi;
+use crate::requests::{Event, RequestRejected};
+use serde::{Deserialize, Serialize};
+use std::cmp::min;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{watch, Notify};
+
+/// Delay before the first retry after a failed upload. Doubles with each consecutive failure.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 10);
+
+/// Subdirectory of the queue holding events the server rejected
+const REJECTED_DIR: &str = "rejected";
+
+/// Extension of events still being written
+const TMP_EXTENSION: &str = "tmp";
+
+/// How often to re-scan the queue directory when no new events are pushed
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(60 * 5);
+
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq)]
+pub(crate) struct QueuedEvent {
+    /// When the event was captured, in milliseconds since the Unix epoch
+    pub(crate) timestamp: u64,
+    #[serde(flatten)]
+    pub(crate) event: Event,
+}
+
+impl QueuedEvent {
+    pub(crate) fn new(captured_at: SystemTime, event: Event) -> Self {
+        Self {
+            timestamp: captured_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            event,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct EventQueue {
+    inner: Arc<QueueInner>,
+}
+
+struct QueueInner {
+    dir: PathBuf,
+    seq: AtomicU64,
+    notify: Notify,
+}
+
+impl EventQueue {
+    /// Opens the queue stored in `dir`, creating the directory if needed. Events left over from
+    /// a previous run are kept, while writes it didn't finish are removed.
+    pub(crate) fn open(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(REJECTED_DIR))?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == TMP_EXTENSION) {
+                warn!("Removing unfinished queued event {:?}", path);
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(Self {
+            inner: Arc::new(QueueInner {
+                dir,
+                seq: AtomicU64::new(0),
+                notify: Notify::new(),
+            }),
+        })
+    }
+
+    /// Durably stores an event and wakes the uploader.
+    pub(crate) fn push(&self, event: &QueuedEvent) -> Result<(), Box<dyn Error>> {
+        // Zero-padded so that file names sort in capture order
+        let name = format!(
+            "{:020}-{:010}-{:010}",
+            event.timestamp,
+            std::process::id(),
+            self.inner.seq.fetch_add(1, Ordering::SeqCst)
+        );
+        let tmp_path = self.inner.dir.join(format!("{}.{}", name, TMP_EXTENSION));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(event)?)?;
+        file.sync_all()?;
+        // Rename is atomic, so a crash never leaves a partially written event in the queue
+        fs::rename(&tmp_path, self.inner.dir.join(format!("{}.json", name)))?;
+        // Make the rename itself durable
+        File::open(&self.inner.dir)?.sync_all()?;
+
+        self.inner.notify.notify_one();
+        Ok(())
+    }
+
+    /// Paths of all queued events, oldest first
+    pub(crate) fn pending(&self) -> std::io::Result<Vec<PathBuf>> {
+        let mut paths = fs::read_dir(&self.inner.dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<PathBuf>>();
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn load(path: &Path) -> std::io::Result<QueuedEvent> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Posts queued events in capture order, removing each one once the server has accepted it.
+    ///
+    /// Stops at the first upload which may succeed later, such as while offline, so that ordering
+    /// is preserved. An event the server rejects outright is moved aside, since sending it again
+    /// can't succeed. Returns the number of events uploaded.
+    pub(crate) async fn drain(
+        &self,
+        api: &dyn AccountabilityApi,
+        device: &mut Device,
+    ) -> Result<usize, Box<dyn Error>> {
+        let mut uploaded = 0;
+        for path in self.pending()? {
+            let event = match Self::load(&path) {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Discarding unreadable queued event {:?}: {}", path, e);
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+            };
+
+            match api.post_event(device, event.timestamp, event.event).await {
+                Ok(()) => {
+                    fs::remove_file(&path)?;
+                    uploaded += 1;
+                }
+                Err(e)
+                    if e.downcast_ref::<RequestRejected>()
+                        .is_some_and(RequestRejected::is_permanent) =>
+                {
+                    warn!("Server rejected queued event {:?}: {}", path, e);
+                    self.reject(&path)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(uploaded)
+    }
+
+    /// Moves a queued event to the rejected directory, where it is kept but never sent again
+    fn reject(&self, path: &Path) -> std::io::Result<()> {
+        let name = path.file_name().ok_or(std::io::ErrorKind::InvalidInput)?;
+        fs::rename(path, self.inner.dir.join(REJECTED_DIR).join(name))
+    }
+
+    /// Paths of the events the server rejected, oldest first
+    #[cfg(test)]
+    fn rejected(&self) -> std::io::Result<Vec<PathBuf>> {
+        let mut paths = fs::read_dir(self.inner.dir.join(REJECTED_DIR))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .collect::<Vec<PathBuf>>();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Waits until an event is pushed, or until `timeout` elapses
+    async fn wait_for_push(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.inner.notify.notified()).await;
+    }
+}
+
+/// Uploads queued events for as long as the program runs, backing off exponentially while the
+/// server is unreachable. Picks up the device's credentials from `credentials` whenever the user
+/// signs in again.
+pub(crate) async fn run_uploader(
+    queue: EventQueue,
+    api: Arc<dyn AccountabilityApi>,
+    mut credentials: watch::Receiver<Device>,
+) {
+    let mut device = credentials.borrow_and_update().clone();
+    let mut retry_delay = MIN_RETRY_DELAY;
+    loop {
+        if credentials.has_changed().unwrap_or(false) {
+            device = credentials.borrow_and_update().clone();
+        }
+        let success = match queue.drain(api.as_ref(), &mut device).await {
+            Ok(uploaded) => {
+                if uploaded > 0 {
+                    info!("Uploaded {} queued events", uploaded);
+                }
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to upload queued events, retrying in {:?}: {}",
+                    retry_delay, e
+                );
+                false
+            }
+        };
+
+        if success {
+            retry_delay = MIN_RETRY_DELAY;
+            queue.wait_for_push(IDLE_POLL_INTERVAL).await;
+        } else {
+            tokio::time::sleep(retry_delay).await;
+            retry_delay = min(retry_delay * 2, MAX_RETRY_DELAY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::requests::api::MockAccountabilityApi;
+    use crate::requests::EventReport;
+    use crate::test_util::{temp_dir, test_device};
+    use mockall::Sequence;
+    use pretty_assertions::assert_eq;
+    use reqwest::StatusCode;
+    use tempfile::TempDir;
+
+    /// A queue in its own directory, which is removed along with the returned `TempDir`
+    fn test_queue() -> (TempDir, EventQueue) {
+        let dir = temp_dir();
+        let queue = EventQueue::open(dir.path()).unwrap();
+        (dir, queue)
+    }
+
+    fn event(timestamp: u64, count: i32) -> QueuedEvent {
+        let mut report = EventReport::default();
+        report.event.insert("testkeyword1".to_string(), count);
+        QueuedEvent {
+            timestamp,
+            event: Event::Keywords(report),
+        }
+    }
+
+    #[test]
+    fn test_events_survive_reopen() {
+        let (_dir, queue) = test_queue();
+        queue.push(&event(2, 1)).unwrap();
+        queue.push(&event(1, 1)).unwrap();
+
+        let reopened = EventQueue::open(&queue.inner.dir).unwrap();
+        let pending = reopened.pending().unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(EventQueue::load(&pending[0]).unwrap(), event(1, 1));
+    }
+
+    /// A write interrupted by a crash is removed when the queue is next opened
+    #[test]
+    fn test_open_removes_unfinished_events() {
+        let (_dir, queue) = test_queue();
+        queue.push(&event(1, 1)).unwrap();
+        let unfinished = queue
+            .inner
+            .dir
+            .join("00000000000000000002-0000000001-0000000000.tmp");
+        fs::write(&unfinished, "{\"timest").unwrap();
+
+        let reopened = EventQueue::open(&queue.inner.dir).unwrap();
+        assert!(!unfinished.exists());
+        let pending = reopened.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(EventQueue::load(&pending[0]).unwrap(), event(1, 1));
+    }
+
+    /// Events stay queued while the server is unreachable, then are replayed in capture order
+    /// with their original timestamps
+    #[tokio::test]
+    async fn test_drain_replays_in_order() {
+        let (_dir, queue) = test_queue();
+        queue.push(&event(300, 3)).unwrap();
+        queue.push(&event(100, 1)).unwrap();
+        queue.push(&event(200, 2)).unwrap();
+
+        let mut offline = MockAccountabilityApi::new();
+        offline
+            .expect_post_event()
+            .times(1)
+            .returning(|_, _, _| Err("no internet connection".into()));
+        let mut device = test_device();
+        assert!(queue.drain(&offline, &mut device).await.is_err());
+        assert_eq!(queue.pending().unwrap().len(), 3);
+
+        let mut online = MockAccountabilityApi::new();
+        let mut seq = Sequence::new();
+        for (timestamp, count) in [(100, 1), (200, 2), (300, 3)] {
+            online
+                .expect_post_event()
+                .times(1)
+                .in_sequence(&mut seq)
+                .withf(move |_, t, e| {
+                    *t == timestamp
+                        && matches!(e, Event::Keywords(r) if r.event["testkeyword1"] == count)
+                })
+                .returning(|_, _, _| Ok(()));
+        }
+        assert_eq!(queue.drain(&online, &mut device).await.unwrap(), 3);
+        assert!(queue.pending().unwrap().is_empty());
+    }
+
+    /// A rejected event is moved aside and the rest are still uploaded, while a server error
+    /// leaves everything queued
+    #[tokio::test]
+    async fn test_drain_moves_rejected_events_aside() {
+        let (_dir, queue) = test_queue();
+        queue.push(&event(100, 1)).unwrap();
+        queue.push(&event(200, 2)).unwrap();
+
+        let mut unavailable = MockAccountabilityApi::new();
+        unavailable
+            .expect_post_event()
+            .times(1)
+            .returning(|_, _, _| {
+                Err(RequestRejected {
+                    action: "Posting event",
+                    status: StatusCode::SERVICE_UNAVAILABLE,
+                }
+                .into())
+            });
+        let mut device = test_device();
+        assert!(queue.drain(&unavailable, &mut device).await.is_err());
+        assert_eq!(queue.pending().unwrap().len(), 2);
+        assert!(queue.rejected().unwrap().is_empty());
+
+        let mut api = MockAccountabilityApi::new();
+        api.expect_post_event()
+            .times(2)
+            .returning(|_, timestamp, _| match timestamp {
+                100 => Err(RequestRejected {
+                    action: "Posting event",
+                    status: StatusCode::BAD_REQUEST,
+                }
+                .into()),
+                _ => Ok(()),
+            });
+        assert_eq!(queue.drain(&api, &mut device).await.unwrap(), 1);
+        assert!(queue.pending().unwrap().is_empty());
+        let rejected = queue.rejected().unwrap();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(EventQueue::load(&rejected[0]).unwrap(), event(100, 1));
+    }
+
+    #[tokio::test]
+    async fn test_drain_discards_corrupt_event() {
+        let (_dir, queue) = test_queue();
+        fs::write(
+            queue.inner.dir.join("00000000000000000001-truncated.json"),
+            "{\"time",
+        )
+        .unwrap();
+        queue.push(&event(5, 1)).unwrap();
+
+        let mut api = MockAccountabilityApi::new();
+        api.expect_post_event()
+            .times(1)
+            .withf(|_, t, _| *t == 5)
+            .returning(|_, _, _| Ok(()));
+        assert_eq!(queue.drain(&api, &mut test_device()).await.unwrap(), 1);
+        assert!(queue.pending().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_uploader_picks_up_pushed_events() {
+        let (_dir, queue) = test_queue();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut api = MockAccountabilityApi::new();
+        api.expect_post_event().returning(move |_, t, _| {
+            tx.send(t).unwrap();
+            Ok(())
+        });
+
+        let (_credentials, receiver) = watch::channel(test_device());
+        tokio::spawn(run_uploader(queue.clone(), Arc::new(api), receiver));
+        queue.push(&event(42, 1)).unwrap();
+
+        assert_eq!(rx.recv().await, Some(42));
+    }
+}
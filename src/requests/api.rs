@@ -0,0 +1,371 @@
+use crate::attestation::build_info;
+use crate::auth::register::Device;
+use crate::auth::{Token, TokenReqBody};
+use crate::requests::{
+    make_request_with_id_token, refresh_id_token, send_with_id_token, BlacklistJson,
+    CheckSafeExitIdJson, DeviceRegisterJson, Event, EventBodyJson, GetSafeExitIdJson,
+    RequestRejected,
+};
+use async_trait::async_trait;
+use fireauth::FireAuth;
+#[cfg(test)]
+use mockall::automock;
+use reqwest::{Client, StatusCode};
+use std::error::Error;
+
+/// Every call the daemon makes to the OpenAccountability server.
+///
+/// The daemon talks to the server through [`HttpApi`]; tests substitute `MockAccountabilityApi`
+/// so that authentication and monitoring can run without credentials or network access.
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub(crate) trait AccountabilityApi: Send + Sync {
+    /// Exchange a refresh token for a new id token
+    async fn refresh_id_token(&self, refresh_token: &str) -> Result<Token, Box<dyn Error>>;
+
+    /// Fetch the keyword blacklist
+    async fn get_blacklist(&self, device: &mut Device) -> Result<BlacklistJson, Box<dyn Error>>;
+
+    /// Register the device under the signed-in user, returning the device's new uuid
+    async fn register_device(&self, device: &mut Device) -> Result<String, Box<dyn Error>>;
+
+    /// Request a new safe exit id for the device
+    async fn get_safe_exit_id(&self, device: &mut Device) -> Result<String, Box<dyn Error>>;
+
+    /// Returns whether the server accepts the device's stored safe exit id
+    async fn check_safe_exit_id(&self, device: &mut Device) -> Result<bool, Box<dyn Error>>;
+
+    /// Report an event which happened at `timestamp` (milliseconds since the Unix epoch)
+    async fn post_event(
+        &self,
+        device: &mut Device,
+        timestamp: u64,
+        event: Event,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Tell the server that the program is exiting outside of a system shutdown
+    async fn report_exit(&self, device: &mut Device) -> Result<(), Box<dyn Error>>;
+}
+
+/// [`AccountabilityApi`] implementation backed by the cloud functions server
+pub(crate) struct HttpApi {
+    client: Client,
+    fire_auth: FireAuth,
+    base_url: String,
+}
+
+impl HttpApi {
+    pub fn new(client: Client, api_key: String, base_url: &str) -> Self {
+        Self {
+            client,
+            fire_auth: FireAuth::new(api_key),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+}
+
+#[async_trait]
+impl AccountabilityApi for HttpApi {
+    async fn refresh_id_token(&self, refresh_token: &str) -> Result<Token, Box<dyn Error>> {
+        refresh_id_token(refresh_token.to_string(), &self.fire_auth).await
+    }
+
+    async fn get_blacklist(&self, device: &mut Device) -> Result<BlacklistJson, Box<dyn Error>> {
+        let request_builder = self.client.get(self.url("/getBlacklist"));
+        let res =
+            send_with_id_token(&self.fire_auth, device, request_builder, |req, _| req).await?;
+
+        Ok(res.json().await?)
+    }
+
+    async fn register_device(&self, device: &mut Device) -> Result<String, Box<dyn Error>> {
+        let mut request_json = DeviceRegisterJson {
+            id_token: device.id_token.clone(),
+            device_name: device.name.clone(),
+            build: build_info().clone(),
+        };
+        let request_builder = self.client.post(self.url("/api/device"));
+        let res =
+            make_request_with_id_token(&self.fire_auth, device, request_builder, &mut request_json)
+                .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.text().await?),
+            status => Err(format!("Failed to register device: {}", status).into()),
+        }
+    }
+
+    async fn get_safe_exit_id(&self, device: &mut Device) -> Result<String, Box<dyn Error>> {
+        let mut request_json = GetSafeExitIdJson {
+            id_token: device.id_token.clone(),
+            device_uuid: device.uuid.clone(),
+        };
+        let request_builder = self.client.post(self.url("/api/device/safe_exit_id"));
+        let res =
+            make_request_with_id_token(&self.fire_auth, device, request_builder, &mut request_json)
+                .await?;
+
+        Ok(res.text().await?)
+    }
+
+    async fn check_safe_exit_id(&self, device: &mut Device) -> Result<bool, Box<dyn Error>> {
+        let mut request_json = CheckSafeExitIdJson {
+            id_token: device.id_token.clone(),
+            device_uuid: device.uuid.clone(),
+            safe_exit_id: device.safe_shutdown_id.clone(),
+        };
+        let request_builder = self.client.patch(self.url("/api/device/safe_exit_id"));
+        let res =
+            make_request_with_id_token(&self.fire_auth, device, request_builder, &mut request_json)
+                .await?;
+
+        Ok(res.status().is_success())
+    }
+
+    async fn post_event(
+        &self,
+        device: &mut Device,
+        timestamp: u64,
+        event: Event,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut request_json = EventBodyJson {
+            id_token: device.id_token.clone(),
+            device_uuid: device.uuid.clone(),
+            timestamp,
+            event,
+            build: build_info().clone(),
+        };
+        let request_builder = self.client.post(self.url("/api/event"));
+        let res =
+            make_request_with_id_token(&self.fire_auth, device, request_builder, &mut request_json)
+                .await?;
+
+        if !res.status().is_success() {
+            return Err(RequestRejected {
+                action: "Posting event",
+                status: res.status(),
+            }
+            .into());
+        }
+        let response_text = res.text().await?;
+        info!("response: {}", response_text);
+        Ok(())
+    }
+
+    async fn report_exit(&self, device: &mut Device) -> Result<(), Box<dyn Error>> {
+        let mut request_json = TokenReqBody {
+            id_token: device.id_token.clone(),
+            device_id: device.uuid.clone(),
+        };
+        let request_builder = self.client.patch(self.url("/api/device"));
+        let res =
+            make_request_with_id_token(&self.fire_auth, device, request_builder, &mut request_json)
+                .await?;
+
+        if !res.status().is_success() {
+            return Err(RequestRejected {
+                action: "Reporting exit",
+                status: res.status(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::capture::BlindReason;
+    use crate::requests::mock_server::MockServer;
+    use crate::requests::EventReport;
+    use pretty_assertions::assert_eq;
+
+    fn test_device() -> Device {
+        Device {
+            refresh_token: "refresh".to_string(),
+            id_token: "id".to_string(),
+            uuid: "uuid".to_string(),
+            name: "laptop".to_string(),
+            safe_shutdown_id: "exitid".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_device() {
+        let server = MockServer::start(|_| (200, "new-uuid".to_string())).await;
+        let api = HttpApi::new(Client::new(), "".to_string(), &server.base_url);
+        let mut device = test_device();
+
+        let uuid = api.register_device(&mut device).await.unwrap();
+        assert_eq!(uuid, "new-uuid");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/api/device");
+        let body: DeviceRegisterJson = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body.device_name, "laptop");
+        assert_eq!(&body.build, build_info());
+    }
+
+    #[tokio::test]
+    async fn test_register_device_rejected() {
+        let server = MockServer::start(|_| (500, "".to_string())).await;
+        let api = HttpApi::new(Client::new(), "".to_string(), &server.base_url);
+
+        assert!(api.register_device(&mut test_device()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_safe_exit_id() {
+        let server = MockServer::start(|req| match req.method.as_str() {
+            "POST" => (200, "exitid".to_string()),
+            _ => (403, "".to_string()),
+        })
+        .await;
+        let api = HttpApi::new(Client::new(), "".to_string(), &server.base_url);
+        let mut device = test_device();
+
+        assert_eq!(api.get_safe_exit_id(&mut device).await.unwrap(), "exitid");
+        assert!(!api.check_safe_exit_id(&mut device).await.unwrap());
+
+        let requests = server.requests();
+        assert_eq!(requests[1].method, "PATCH");
+        assert_eq!(requests[1].path, "/api/device/safe_exit_id");
+        let body: CheckSafeExitIdJson = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(body.safe_exit_id, "exitid");
+    }
+
+    #[tokio::test]
+    async fn test_post_event() {
+        let server = MockServer::start(|_| (200, "ok".to_string())).await;
+        let api = HttpApi::new(Client::new(), "".to_string(), &server.base_url);
+
+        let mut report = EventReport::default();
+        report.event.insert("testkeyword1".to_string(), 5);
+        report.tier_counts.mid = 5;
+        report.risk_score = 15;
+        api.post_event(&mut test_device(), 1234, Event::Keywords(report.clone()))
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/api/event");
+        assert_eq!(requests[0].header("content-type"), Some("application/json"));
+        let body: EventBodyJson = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body.device_uuid, "uuid");
+        assert_eq!(body.timestamp, 1234);
+        assert_eq!(body.event, Event::Keywords(report));
+        assert_eq!(&body.build, build_info());
+
+        // Tier counts sit alongside the per-keyword counts
+        let json: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(json["type"], "keywords");
+        assert_eq!(json["event"]["testkeyword1"], 5);
+        assert_eq!(json["tier_counts"]["mid"], 5);
+        assert_eq!(json["risk_score"], 15);
+        assert_eq!(json["build"]["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[tokio::test]
+    async fn test_post_capture_blind() {
+        let server = MockServer::start(|_| (200, "ok".to_string())).await;
+        let api = HttpApi::new(Client::new(), "".to_string(), &server.base_url);
+
+        let event = Event::CaptureBlind {
+            screen: 1,
+            reason: BlindReason::Uniform,
+        };
+        api.post_event(&mut test_device(), 1234, event)
+            .await
+            .unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(json["type"], "capture_blind");
+        assert_eq!(json["screen"], 1);
+        assert_eq!(json["reason"], "uniform");
+    }
+
+    #[tokio::test]
+    async fn test_post_event_rejected() {
+        let server = MockServer::start(|_| (503, "".to_string())).await;
+        let api = HttpApi::new(Client::new(), "".to_string(), &server.base_url);
+
+        assert!(api
+            .post_event(
+                &mut test_device(),
+                1234,
+                Event::Keywords(EventReport::default())
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_report_exit_rejected() {
+        let server = MockServer::start(|_| (404, "".to_string())).await;
+        let api = HttpApi::new(Client::new(), "".to_string(), &server.base_url);
+
+        let error = api.report_exit(&mut test_device()).await.unwrap_err();
+        assert!(error
+            .downcast_ref::<RequestRejected>()
+            .is_some_and(RequestRejected::is_permanent));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_stays_local() {
+        let server = MockServer::start(|req| match req.path.as_str() {
+            "/getBlacklist" => (
+                200,
+                r#"{"keywords_high":[],"keywords_mid":[],"keywords_low":[]}"#.to_string(),
+            ),
+            _ => (200, "ok".to_string()),
+        })
+        .await;
+        let api = HttpApi::new(Client::new(), "".to_string(), &server.base_url);
+        let mut device = test_device();
+
+        api.get_blacklist(&mut device).await.unwrap();
+        api.register_device(&mut device).await.unwrap();
+        api.get_safe_exit_id(&mut device).await.unwrap();
+        api.check_safe_exit_id(&mut device).await.unwrap();
+        api.post_event(&mut device, 1234, Event::Keywords(EventReport::default()))
+            .await
+            .unwrap();
+        api.report_exit(&mut device).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 6);
+        for request in requests {
+            assert_eq!(request.header("authorization"), Some("Bearer id"));
+            for (name, value) in &request.headers {
+                assert!(!value.contains("refresh"), "{} header: {}", name, value);
+            }
+            assert!(!request.body.contains("refresh"), "body: {}", request.body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_blacklist() {
+        let server = MockServer::start(|_| {
+            (
+                200,
+                r#"{"keywords_high":["a"],"keywords_mid":["b","c"],"keywords_low":[]}"#.to_string(),
+            )
+        })
+        .await;
+        let api = HttpApi::new(Client::new(), "".to_string(), &server.base_url);
+
+        let blacklist = api.get_blacklist(&mut test_device()).await.unwrap();
+        assert_eq!(blacklist.keywords_high, vec!["a"]);
+        assert_eq!(blacklist.keywords_mid, vec!["b", "c"]);
+        assert!(blacklist.keywords_low.is_empty());
+        assert_eq!(server.requests()[0].path, "/getBlacklist");
+    }
+}
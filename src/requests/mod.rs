@@ -1,5 +1,14 @@
+pub mod api;
+#[cfg(test)]
+pub mod mock_server;
+
+use crate::attestation::BuildInfo;
 use crate::auth::register::Device;
-use crate::auth::Token;
+use crate::auth::{refresh_with_backoff, AuthError, Token, TokenReqBody, ID_TOKEN_REFRESH_MARGIN};
+use crate::capture::BlindReason;
+use crate::journal::PreviousExit;
+use crate::scheduler::gaps::GapKind;
+use crate::tamper::TamperKind;
 use fireauth::FireAuth;
 use reqwest::{RequestBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
@@ -14,6 +23,7 @@ pub(crate) trait RequestJson {
 pub struct DeviceRegisterJson {
     pub(crate) id_token: String,
     pub(crate) device_name: String,
+    pub(crate) build: BuildInfo,
 }
 
 macro_rules! impl_request_json_for_structs {
@@ -32,14 +42,63 @@ impl_request_json_for_structs!(
     DeviceRegisterJson,
     GetSafeExitIdJson,
     CheckSafeExitIdJson,
-    EventBodyJson
+    EventBodyJson,
+    TokenReqBody
 );
 
 #[derive(Serialize, Debug, Deserialize)]
 pub struct EventBodyJson {
     pub(crate) id_token: String,
     pub(crate) device_uuid: String,
+    /// When the event was captured, in milliseconds since the Unix epoch
+    pub(crate) timestamp: u64,
+    #[serde(flatten)]
+    pub(crate) event: Event,
+    /// The build which captured the event
+    pub(crate) build: BuildInfo,
+}
+
+/// Something the daemon reports to the server, tagged with its `type`
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// The keyword hits of a capture cycle
+    Keywords(EventReport),
+    /// A screen couldn't be seen, so a capture cycle had nothing to check
+    CaptureBlind { screen: usize, reason: BlindReason },
+    /// The device file couldn't be read at startup, so the device was registered again
+    DeviceFileCorrupt { reason: String },
+    /// How the previous run of the daemon ended
+    PreviousExit(PreviousExit),
+    /// The machine was suspended through logind between captures
+    Suspended { duration_ms: u64 },
+    /// Captures stopped for longer than scheduled, and it wasn't an announced suspend
+    UnexplainedGap { kind: GapKind, duration_ms: u64 },
+    /// Something was done to stop or weaken the daemon
+    Tamper { kind: TamperKind, detail: String },
+    /// OCR found too few keywords in the embedded canary image, so screenshots can't be trusted
+    /// to be read either
+    OcrUnhealthy { found: i32, expected: i32 },
+}
+
+/// The keyword hits found in one capture cycle
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq)]
+pub struct EventReport {
+    /// Number of hits for each keyword
     pub(crate) event: HashMap<String, i32>,
+    /// Total number of hits in each severity tier
+    #[serde(default)]
+    pub(crate) tier_counts: TierCounts,
+    /// Hits weighted by their tier's severity
+    #[serde(default)]
+    pub(crate) risk_score: i32,
+}
+
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq)]
+pub struct TierCounts {
+    pub(crate) high: i32,
+    pub(crate) mid: i32,
+    pub(crate) low: i32,
 }
 
 #[derive(Serialize, Debug, Deserialize)]
@@ -55,35 +114,123 @@ pub struct CheckSafeExitIdJson {
     pub(crate) safe_exit_id: String,
 }
 
-/// Make a reqwest request, and refresh the id_token if necessary
+#[derive(Serialize, Debug, Deserialize)]
+pub struct DeviceCodeRequestJson {
+    pub(crate) device_name: String,
+}
+
+/// A sign-in code handed out for device-code login
+#[derive(Serialize, Debug, Deserialize)]
+pub struct DeviceCodeJson {
+    /// Identifies the code when polling, and is never shown to the user
+    pub(crate) device_code: String,
+    /// Entered by the user at `verification_uri`
+    pub(crate) user_code: String,
+    pub(crate) verification_uri: String,
+    /// Seconds to wait between polls
+    pub(crate) interval: u64,
+    /// Seconds until the code expires
+    pub(crate) expires_in: u64,
+}
+
+#[derive(Serialize, Debug, Deserialize)]
+pub struct DeviceCodeTokenRequestJson {
+    pub(crate) device_code: String,
+}
+
+/// Why a device code hasn't been exchanged for credentials yet
+#[derive(Serialize, Debug, Deserialize)]
+pub struct DeviceCodeErrorJson {
+    pub(crate) error: String,
+}
+
+/// The keyword lists returned by the server, from most to least severe
+#[derive(Serialize, Debug, Deserialize, Default, Clone)]
+pub struct BlacklistJson {
+    pub(crate) keywords_high: Vec<String>,
+    pub(crate) keywords_mid: Vec<String>,
+    pub(crate) keywords_low: Vec<String>,
+}
+
+/// The server answered a request with an unsuccessful status
+#[derive(thiserror::Error, Debug)]
+#[error("{action} failed: {status}")]
+pub(crate) struct RequestRejected {
+    pub(crate) action: &'static str,
+    pub(crate) status: StatusCode,
+}
+
+impl RequestRejected {
+    /// Whether sending the same request again can't succeed. Server errors and rate limits pass,
+    /// as do credential problems, which are fixed by signing in again rather than by the request.
+    pub(crate) fn is_permanent(&self) -> bool {
+        self.status.is_client_error()
+            && !matches!(
+                self.status,
+                StatusCode::UNAUTHORIZED
+                    | StatusCode::FORBIDDEN
+                    | StatusCode::REQUEST_TIMEOUT
+                    | StatusCode::TOO_MANY_REQUESTS
+            )
+    }
+}
+
+/// Make a reqwest request with the device's id_token in the `Authorization` header and the JSON
+/// body, and refresh the id_token if necessary
 pub(crate) async fn make_request_with_id_token<T: Serialize + ?Sized + RequestJson>(
     fire_auth: &FireAuth,
     device: &mut Device,
     req: RequestBuilder,
     json: &mut T,
 ) -> Result<reqwest::Response, Box<dyn Error>> {
-    let mut original_req = req.try_clone().unwrap();
-    let res = req.send().await?;
+    send_with_id_token(fire_auth, device, req, |req, id_token| {
+        json.set_token_id(id_token);
+        req.json(json)
+    })
+    .await
+}
+
+/// Send a request authenticated with the device's id_token. The id_token is refreshed first if it
+/// is about to expire, and refreshed again (with the request sent again) if the server rejects it.
+/// `build` adds anything else which carries the id_token.
+///
+/// The refresh token is only ever sent to the token endpoint, never to the server.
+pub(crate) async fn send_with_id_token(
+    fire_auth: &FireAuth,
+    device: &mut Device,
+    req: RequestBuilder,
+    mut build: impl FnMut(RequestBuilder, &str) -> RequestBuilder,
+) -> Result<reqwest::Response, Box<dyn Error>> {
+    if device.id_token_expires_within(ID_TOKEN_REFRESH_MARGIN) {
+        device.id_token =
+            refresh_with_backoff(|| refresh_id_token(device.refresh_token.clone(), fire_auth))
+                .await?;
+    }
+    let retry = req.try_clone().ok_or("Request can't be retried")?;
+    let res = build(req, &device.id_token)
+        .bearer_auth(&device.id_token)
+        .send()
+        .await?;
     match res.status() {
         StatusCode::UNAUTHORIZED => {
-            device.id_token = refresh_id_token(device.refresh_token.clone(), fire_auth)
-                .await
-                .unwrap();
-
-            json.set_token_id(&device.id_token);
-            original_req = original_req.json(json);
+            device.id_token =
+                refresh_with_backoff(|| refresh_id_token(device.refresh_token.clone(), fire_auth))
+                    .await?;
             // Run the request again
-            Ok(original_req.send().await?)
+            Ok(build(retry, &device.id_token)
+                .bearer_auth(&device.id_token)
+                .send()
+                .await?)
         }
         StatusCode::PAYMENT_REQUIRED => {
             // No active subscription for the user. Error out.
-            Err("No active subscription".into())
+            Err(AuthError::SubscriptionLapsed.into())
         }
         _ => Ok(res),
     }
 }
 
-/// Refreshes the token. Will loop forever until successful.
+/// Exchanges the refresh token for a new id token, in a single attempt
 pub(crate) async fn refresh_id_token(
     og_token: String,
     auth: &FireAuth,
@@ -0,0 +1,214 @@
+//! Detection of gaps between captures.
+//!
+//! Each capture cycle, from the start of one capture to the start of the next, is timed on three
+//! clocks: the monotonic clock, which stops while the machine is suspended; the boot time clock,
+//! which doesn't; and the wall clock, which can be changed by the user. Comparing them tells
+//! apart a suspend (boot time runs ahead of monotonic time) and a changed clock (wall time
+//! disagrees with boot time). A stopped or stalled process is found by the cycle's sleeps taking
+//! longer than requested, since capturing, throttled OCR and waiting on the user can take
+//! minutes without anything being wrong. Suspends are checked against the ones logind
+//! announced, so that only unannounced ones are reported as unexplained.
+
+use crate::journal::uptime;
+use crate::power::SleepLog;
+use crate::requests::Event;
+use crate::scheduler::Sleeps;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Differences between the clocks, and sleeps overrunning, by less than this are ignored
+const GAP_TOLERANCE: Duration = Duration::from_secs(60);
+
+/// The time on each clock at one moment
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ClockReading {
+    pub(crate) wall: SystemTime,
+    pub(crate) monotonic: Instant,
+    /// Time since boot, including time spent suspended, if it could be read
+    pub(crate) boottime: Option<Duration>,
+}
+
+impl ClockReading {
+    pub(crate) fn now() -> Self {
+        Self {
+            wall: SystemTime::now(),
+            monotonic: Instant::now(),
+            boottime: uptime().ok(),
+        }
+    }
+}
+
+/// One capture cycle, including the wait for the next capture
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Cycle {
+    /// The sleeps of the cycle, including the wait for the next capture
+    pub(crate) sleeps: Sleeps,
+    pub(crate) started: ClockReading,
+    pub(crate) ended: ClockReading,
+}
+
+/// Why captures stopped for longer than scheduled, when it wasn't an announced suspend
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum GapKind {
+    /// The machine was suspended without logind announcing it
+    UnannouncedSuspend,
+    /// The machine kept running but the daemon didn't, e.g. because it was sent `SIGSTOP`
+    Stalled,
+    /// The wall clock was changed
+    ClockJump,
+}
+
+/// Finds gaps between captures, checking suspends against those announced by logind
+#[derive(Default)]
+pub(crate) struct GapDetector {
+    sleeps: SleepLog,
+}
+
+impl GapDetector {
+    pub(crate) fn new(sleeps: SleepLog) -> Self {
+        Self { sleeps }
+    }
+
+    /// The event to report for `cycle`, if it took longer than scheduled
+    pub(crate) fn check(&self, cycle: &Cycle) -> Option<Event> {
+        let monotonic = cycle.ended.monotonic - cycle.started.monotonic;
+        let stalled = cycle.sleeps.measured.saturating_sub(cycle.sleeps.requested);
+        // Suspends and clock changes can only be told apart with the boot time clock
+        let boottime = cycle
+            .started
+            .boottime
+            .zip(cycle.ended.boottime)
+            .map(|(started, ended)| ended.saturating_sub(started));
+        let suspended = boottime.map_or(Duration::ZERO, |boottime| {
+            boottime.saturating_sub(monotonic)
+        });
+        let clock_jump = boottime.map_or(Duration::ZERO, |boottime| {
+            match cycle.ended.wall.duration_since(cycle.started.wall) {
+                Ok(wall) => wall.abs_diff(boottime),
+                Err(e) => e.duration() + boottime,
+            }
+        });
+
+        let announced = self
+            .sleeps
+            .take_between(cycle.started.wall, cycle.ended.wall);
+        let (kind, duration) = if suspended > GAP_TOLERANCE {
+            if announced {
+                return Some(Event::Suspended {
+                    duration_ms: suspended.as_millis() as u64,
+                });
+            }
+            (GapKind::UnannouncedSuspend, suspended)
+        } else if stalled > GAP_TOLERANCE {
+            (GapKind::Stalled, stalled)
+        } else if clock_jump > GAP_TOLERANCE {
+            (GapKind::ClockJump, clock_jump)
+        } else {
+            return None;
+        };
+        Some(Event::UnexplainedGap {
+            kind,
+            duration_ms: duration.as_millis() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    const INTERVAL: Duration = Duration::from_secs(3 * 60);
+
+    /// A cycle which took `monotonic` on the monotonic clock, all of it spent in a wait scheduled
+    /// for `INTERVAL`, `boottime` including suspends and `wall` on the wall clock
+    fn cycle(monotonic: u64, boottime: u64, wall: u64) -> Cycle {
+        let started = ClockReading {
+            wall: SystemTime::now(),
+            monotonic: Instant::now(),
+            boottime: Some(Duration::from_secs(1000)),
+        };
+        Cycle {
+            sleeps: Sleeps {
+                requested: INTERVAL,
+                measured: Duration::from_secs(monotonic),
+            },
+            started,
+            ended: ClockReading {
+                wall: started.wall + Duration::from_secs(wall),
+                monotonic: started.monotonic + Duration::from_secs(monotonic),
+                boottime: started.boottime.map(|b| b + Duration::from_secs(boottime)),
+            },
+        }
+    }
+
+    fn unexplained(kind: GapKind, seconds: u64) -> Option<Event> {
+        Some(Event::UnexplainedGap {
+            kind,
+            duration_ms: seconds * 1000,
+        })
+    }
+
+    #[test]
+    fn test_on_time_cycle_is_not_a_gap() {
+        assert_eq!(GapDetector::default().check(&cycle(185, 185, 185)), None);
+    }
+
+    /// Throttled OCR can make a cycle take many times the interval without stalling
+    #[test]
+    fn test_throttled_cycle_is_not_a_gap() {
+        let mut throttled = cycle(900, 900, 900);
+        throttled.sleeps.requested = Duration::from_secs(890);
+        assert_eq!(GapDetector::default().check(&throttled), None);
+    }
+
+    #[test]
+    fn test_suspends() {
+        let sleeps = SleepLog::default();
+        let detector = GapDetector::new(sleeps.clone());
+
+        // Suspended for an hour without logind knowing
+        assert_eq!(
+            detector.check(&cycle(180, 3780, 3780)),
+            unexplained(GapKind::UnannouncedSuspend, 3600)
+        );
+
+        let cycle = cycle(180, 3780, 3780);
+        sleeps.record(cycle.started.wall + Duration::from_secs(60));
+        assert_eq!(
+            detector.check(&cycle),
+            Some(Event::Suspended {
+                duration_ms: 3600 * 1000
+            })
+        );
+    }
+
+    #[test]
+    fn test_stalled_and_clock_jump() {
+        let detector = GapDetector::default();
+        assert_eq!(
+            detector.check(&cycle(900, 900, 900)),
+            unexplained(GapKind::Stalled, 720)
+        );
+        assert_eq!(
+            detector.check(&cycle(180, 180, 7380)),
+            unexplained(GapKind::ClockJump, 7200)
+        );
+    }
+
+    /// Without the boot time clock, a suspend or clock change can't be told from an on-time
+    /// cycle, but a stall still can
+    #[test]
+    fn test_unknown_boottime() {
+        let detector = GapDetector::default();
+        let mut suspended = cycle(180, 3780, 3780);
+        suspended.ended.boottime = None;
+        assert_eq!(detector.check(&suspended), None);
+
+        let mut stalled = cycle(900, 900, 900);
+        stalled.started.boottime = None;
+        assert_eq!(detector.check(&stalled), unexplained(GapKind::Stalled, 720));
+    }
+}
@@ -0,0 +1,196 @@
+//! Timing of the capture cycles.
+//!
+//! Every wait goes through a [`Scheduler`], which wakes up as soon as shutdown is requested
+//! instead of finishing the sleep. How long to wait between captures is decided by an
+//! [`IntervalStrategy`]. Cycles which take longer than scheduled are found by the [`gaps`]
+//! module.
+
+pub(crate) mod gaps;
+
+use crate::scheduler::gaps::{ClockReading, Cycle};
+use crate::{OpenAccError, MAX_SLEEP_SECONDS, MIN_SLEEP_SECONDS};
+use rand::Rng;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Decides how long to wait before each capture
+pub(crate) trait IntervalStrategy: Send {
+    fn next_interval(&mut self) -> Duration;
+}
+
+/// Waits a uniformly random time between `min` and `max`, so that captures can't be predicted
+pub(crate) struct UniformJitter {
+    pub(crate) min: Duration,
+    pub(crate) max: Duration,
+}
+
+impl Default for UniformJitter {
+    fn default() -> Self {
+        UniformJitter {
+            min: Duration::from_secs(MIN_SLEEP_SECONDS),
+            max: Duration::from_secs(MAX_SLEEP_SECONDS),
+        }
+    }
+}
+
+impl IntervalStrategy for UniformJitter {
+    fn next_interval(&mut self) -> Duration {
+        rand::thread_rng().gen_range(self.min..=self.max)
+    }
+}
+
+/// Always waits the same time
+#[cfg(test)]
+pub(crate) struct FixedInterval(pub(crate) Duration);
+
+#[cfg(test)]
+impl IntervalStrategy for FixedInterval {
+    fn next_interval(&mut self) -> Duration {
+        self.0
+    }
+}
+
+/// Time spent sleeping since the last capture, as requested and as measured on the monotonic
+/// clock
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Sleeps {
+    pub(crate) requested: Duration,
+    pub(crate) measured: Duration,
+}
+
+pub(crate) struct Scheduler {
+    shutdown: CancellationToken,
+    strategy: Box<dyn IntervalStrategy>,
+    /// Every sleep of the current cycle, including throttling and retries
+    sleeps: Mutex<Sleeps>,
+}
+
+impl Scheduler {
+    /// `shutdown` is cancelled by the signal handlers when the program should stop
+    pub(crate) fn new(shutdown: CancellationToken, strategy: Box<dyn IntervalStrategy>) -> Self {
+        Self {
+            shutdown,
+            strategy,
+            sleeps: Mutex::new(Sleeps::default()),
+        }
+    }
+
+    pub(crate) fn is_shutdown(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    /// Sleeps for `duration`, or returns [`OpenAccError::SigTerm`] as soon as shutdown is
+    /// requested
+    pub(crate) async fn sleep(&self, duration: Duration) -> Result<(), OpenAccError> {
+        let start = Instant::now();
+        tokio::select! {
+            _ = self.shutdown.cancelled() => {
+                info!("Exiting due to SIGTERM");
+                return Err(OpenAccError::SigTerm);
+            }
+            _ = tokio::time::sleep(duration) => {}
+        }
+        let mut sleeps = self.sleeps.lock().unwrap();
+        sleeps.requested += duration;
+        sleeps.measured += start.elapsed();
+        Ok(())
+    }
+
+    /// Sleeps until the next capture is due, timing the whole cycle since `started` so that
+    /// gaps can be detected
+    pub(crate) async fn wait_for_next_capture(
+        &mut self,
+        started: ClockReading,
+    ) -> Result<Cycle, OpenAccError> {
+        let interval = self.strategy.next_interval();
+        info!("next capture in {:?}", interval);
+        self.sleep(interval).await?;
+        Ok(Cycle {
+            sleeps: std::mem::take(&mut *self.sleeps.lock().unwrap()),
+            started,
+            ended: ClockReading::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_uniform_jitter_within_bounds() {
+        let mut strategy = UniformJitter {
+            min: Duration::from_secs(2),
+            max: Duration::from_secs(5),
+        };
+        for _ in 0..1000 {
+            let interval = strategy.next_interval();
+            assert!(interval >= strategy.min && interval <= strategy.max);
+        }
+
+        let mut fixed = UniformJitter {
+            min: Duration::from_secs(3),
+            max: Duration::from_secs(3),
+        };
+        assert_eq!(fixed.next_interval(), Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_interrupts_sleep() {
+        let shutdown = CancellationToken::new();
+        let mut scheduler = Scheduler::new(
+            shutdown.clone(),
+            Box::new(FixedInterval(Duration::from_secs(60 * 60))),
+        );
+
+        let cancel = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            shutdown.cancel();
+        });
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            scheduler.wait_for_next_capture(ClockReading::now()),
+        )
+        .await
+        .expect("shutdown should interrupt the sleep");
+        assert!(matches!(result, Err(OpenAccError::SigTerm)));
+        assert!(scheduler.is_shutdown());
+        cancel.await.unwrap();
+    }
+
+    /// A cycle's sleeps include throttling on top of the wait for the next capture, and start
+    /// over with the next cycle
+    #[tokio::test(start_paused = true)]
+    async fn test_cycle_sleeps() {
+        let mut scheduler = Scheduler::new(
+            CancellationToken::new(),
+            Box::new(FixedInterval(Duration::from_secs(3 * 60))),
+        );
+        scheduler.sleep(Duration::from_secs(10 * 60)).await.unwrap();
+        let cycle = scheduler
+            .wait_for_next_capture(ClockReading::now())
+            .await
+            .unwrap();
+        assert_eq!(cycle.sleeps.requested, Duration::from_secs(13 * 60));
+        assert_eq!(cycle.sleeps.measured, Duration::from_secs(13 * 60));
+
+        let cycle = scheduler
+            .wait_for_next_capture(ClockReading::now())
+            .await
+            .unwrap();
+        assert_eq!(cycle.sleeps.requested, Duration::from_secs(3 * 60));
+    }
+
+    #[tokio::test]
+    async fn test_sleep_completes() {
+        let scheduler = Scheduler::new(
+            CancellationToken::new(),
+            Box::new(FixedInterval(Duration::ZERO)),
+        );
+        scheduler.sleep(Duration::from_millis(1)).await.unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+//! Detection of attempts to stop or weaken the daemon.
+//!
+//! Each problem found is a [`Finding`], which is reported to the server as a tamper event. The
+//! service unit is checked at startup by [`unit`], the files the daemon depends on are watched
+//! while it runs by [`watcher`], and the daemon's own process is checked periodically by
+//! [`process`].
+
+pub(crate) mod process;
+pub(crate) mod unit;
+pub(crate) mod watcher;
+
+use crate::requests::Event;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What was tampered with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TamperKind {
+    /// The service unit can't be found in any systemd search path
+    UnitMissing,
+    /// The service unit is masked, so systemd won't start it
+    UnitMasked,
+    /// The service unit isn't enabled, so it won't start at boot
+    UnitDisabled,
+    /// `ExecStart` doesn't run this binary
+    ExecStartMismatch,
+    /// `Restart` is no longer `always`, so the daemon stays stopped once killed
+    RestartChanged,
+    /// `RestartSec` was changed
+    RestartDelayChanged,
+    /// A file the daemon depends on was changed or created while it was running
+    FileModified,
+    /// A file the daemon depends on was deleted or moved away while it was running
+    FileDeleted,
+    /// A debugger or other tracer is attached to the daemon
+    DebuggerAttached,
+    /// A variable which makes the dynamic loader load other code was set for the daemon
+    LoaderVariableSet,
+    /// Executable code was mapped from a file outside the system's library directories
+    UnexpectedLibrary,
+}
+
+/// One problem found by a tamper check
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Finding {
+    pub(crate) kind: TamperKind,
+    /// What exactly was found, for the partner to look into
+    pub(crate) detail: String,
+}
+
+impl Finding {
+    pub(crate) fn new(kind: TamperKind, detail: impl Into<String>) -> Self {
+        Self {
+            kind,
+            detail: detail.into(),
+        }
+    }
+
+    pub(crate) fn into_event(self) -> Event {
+        Event::Tamper {
+            kind: self.kind,
+            detail: self.detail,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.detail)
+    }
+}
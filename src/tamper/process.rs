@@ -0,0 +1,252 @@
+//! Checks the daemon's own process for a debugger or injected code.
+//!
+//! A debugger attached through ptrace can patch the daemon in memory, and the dynamic loader's
+//! variables or `dlopen` can swap in code which stubs out OCR or screen capture. All of it shows
+//! up in `/proc`: the tracer in `status`, the variables in `environ` and the code mapped into the
+//! process in `maps`.
+
+use crate::queue::{EventQueue, QueuedEvent};
+use crate::tamper::{Finding, TamperKind};
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// How often the process is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Variables which make the dynamic loader load other code into the process
+const LOADER_VARIABLES: [&str; 3] = ["LD_PRELOAD", "LD_LIBRARY_PATH", "LD_AUDIT"];
+
+/// Where the system's package manager installs libraries. Code mapped from anywhere else was put
+/// in by hand.
+const TRUSTED_LIBRARY_DIRS: [&str; 5] = [
+    "/usr/lib/",
+    "/usr/lib64/",
+    "/lib/",
+    "/lib64/",
+    "/usr/local/lib/",
+];
+
+/// The process tracing this one, from the contents of `/proc/<pid>/status`
+fn tracer_pid(status: &str) -> Option<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("TracerPid:"))
+        .and_then(|pid| pid.trim().parse().ok())
+        .filter(|&pid| pid != 0)
+}
+
+/// The loader variables set in the contents of `/proc/<pid>/environ`, as `NAME=value`
+fn loader_variables(environ: &[u8]) -> Vec<String> {
+    environ
+        .split(|&b| b == 0)
+        .map(String::from_utf8_lossy)
+        .filter(|var| {
+            var.split_once('=')
+                .is_some_and(|(name, _)| LOADER_VARIABLES.contains(&name))
+        })
+        .map(|var| var.into_owned())
+        .collect()
+}
+
+/// The files mapped executable from outside the trusted directories, from the contents of
+/// `/proc/<pid>/maps`. The process's own executable `exe` is left out, since the watcher and the
+/// attestation already cover it.
+fn untrusted_executable_mappings(maps: &str, exe: &str) -> BTreeSet<String> {
+    maps.lines()
+        .filter_map(|line| {
+            // The path is the sixth field, and may itself contain spaces
+            let mut fields = line.splitn(6, ' ');
+            let permissions = fields.nth(1)?;
+            let path = fields.nth(3)?;
+            permissions.contains('x').then_some(path)
+        })
+        .map(|path| path.trim_start().trim_end_matches(" (deleted)"))
+        .filter(|path| path.starts_with('/') && *path != exe)
+        .filter(|path| !TRUSTED_LIBRARY_DIRS.iter().any(|dir| path.starts_with(dir)))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Checks one process, reporting each finding once
+pub(crate) struct ProcessChecker {
+    /// The process's directory in `/proc`
+    proc_dir: PathBuf,
+    reported: HashSet<(TamperKind, String)>,
+}
+
+impl ProcessChecker {
+    pub(crate) fn new(proc_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            proc_dir: proc_dir.into(),
+            reported: HashSet::new(),
+        }
+    }
+
+    /// Checks the daemon's own process
+    pub(crate) fn current() -> Self {
+        Self::new("/proc/self")
+    }
+
+    fn findings(&self) -> io::Result<Vec<Finding>> {
+        let mut findings = Vec::new();
+
+        let status = fs::read_to_string(self.proc_dir.join("status"))?;
+        if let Some(pid) = tracer_pid(&status) {
+            let name = fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+            findings.push(Finding::new(
+                TamperKind::DebuggerAttached,
+                format!("traced by process {} ({})", pid, name.trim()),
+            ));
+        }
+
+        let environ = fs::read(self.proc_dir.join("environ"))?;
+        findings.extend(
+            loader_variables(&environ)
+                .into_iter()
+                .map(|var| Finding::new(TamperKind::LoaderVariableSet, var)),
+        );
+
+        let maps = fs::read_to_string(self.proc_dir.join("maps"))?;
+        let exe = fs::read_link(self.proc_dir.join("exe"))?;
+        let exe = exe.to_string_lossy();
+        findings.extend(
+            untrusted_executable_mappings(&maps, exe.trim_end_matches(" (deleted)"))
+                .into_iter()
+                .map(|path| Finding::new(TamperKind::UnexpectedLibrary, path)),
+        );
+        Ok(findings)
+    }
+
+    /// The findings which haven't been reported by an earlier check
+    pub(crate) fn check(&mut self) -> io::Result<Vec<Finding>> {
+        let mut findings = self.findings()?;
+        findings.retain(|finding| self.reported.insert((finding.kind, finding.detail.clone())));
+        Ok(findings)
+    }
+}
+
+/// Checks the process periodically, queueing a tamper event for each new finding
+pub(crate) async fn run_process_checks(mut checker: ProcessChecker, queue: EventQueue) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let findings = match checker.check() {
+            Ok(findings) => findings,
+            Err(e) => {
+                error!("Failed to check the process: {}", e);
+                continue;
+            }
+        };
+        for finding in findings {
+            warn!("Process tampered with: {}", finding);
+            if let Err(e) = queue.push(&QueuedEvent::new(SystemTime::now(), finding.into_event())) {
+                error!("Failed to queue tamper event: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_dir;
+    use pretty_assertions::assert_eq;
+    use std::process::Command;
+    use std::time::Instant;
+
+    #[test]
+    fn test_tracer_pid() {
+        let status = "Name:\topen-accountab\nState:\tS (sleeping)\nTracerPid:\t0\n";
+        assert_eq!(tracer_pid(status), None);
+        let status = "Name:\topen-accountab\nState:\tt (tracing stop)\nTracerPid:\t4242\n";
+        assert_eq!(tracer_pid(status), Some(4242));
+    }
+
+    #[test]
+    fn test_loader_variables_and_mappings() {
+        let environ = b"HOME=/root\0LD_PRELOAD=/tmp/shim.so\0LD_PRELOAD_NOT=x\0LD_AUDIT=a.so\0";
+        assert_eq!(
+            loader_variables(environ),
+            vec!["LD_PRELOAD=/tmp/shim.so", "LD_AUDIT=a.so"]
+        );
+
+        let maps = "\
+55d0c0a00000-55d0c0a20000 r--p 00000000 08:01 1234    /usr/bin/open-accountability
+55d0c0a20000-55d0c0a80000 r-xp 00020000 08:01 1234    /usr/bin/open-accountability
+7f1c00000000-7f1c00028000 r--p 00000000 08:01 2345    /usr/lib/x86_64-linux-gnu/libc.so.6
+7f1c00028000-7f1c001bd000 r-xp 00028000 08:01 2345    /usr/lib/x86_64-linux-gnu/libc.so.6
+7f1c00100000-7f1c00101000 r-xp 00000000 08:01 3456    /home/user/.cache/my shim.so (deleted)
+7f1c00200000-7f1c00201000 r-xp 00001000 08:01 3456    /home/user/.cache/my shim.so (deleted)
+7f1c00210000-7f1c00211000 r-xp 00000000 08:01 4567    /tmp/stub
+7f1c00220000-7f1c00221000 r--p 00000000 08:01 5678    /home/user/notes.txt
+7f1c00300000-7f1c00301000 rw-p 00000000 00:00 0
+7ffd00000000-7ffd00021000 rw-p 00000000 00:00 0       [stack]
+7ffd00100000-7ffd00102000 r-xp 00000000 00:00 0       [vdso]
+";
+        assert_eq!(
+            untrusted_executable_mappings(maps, "/usr/bin/open-accountability")
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["/home/user/.cache/my shim.so", "/tmp/stub"]
+        );
+    }
+
+    /// A process started under a preloaded library is caught by both the variable and the
+    /// library, and each is only reported once
+    #[test]
+    fn test_preloaded_process() {
+        // Any library works as the preload, as long as it's loaded from an untrusted directory
+        let own_maps = fs::read_to_string("/proc/self/maps").unwrap();
+        let libc = own_maps
+            .lines()
+            .filter_map(|line| line.split_whitespace().nth(5))
+            .find(|path| path.contains("/libc.so"))
+            .expect("libc isn't mapped");
+        let dir = temp_dir();
+        let preload = dir.path().join("libshim.so");
+        fs::copy(libc, &preload).unwrap();
+
+        let mut child = Command::new("sleep")
+            .arg("30")
+            .env("LD_PRELOAD", &preload)
+            // Set by cargo for the tests themselves
+            .env_remove("LD_LIBRARY_PATH")
+            .spawn()
+            .unwrap();
+        let proc_dir = PathBuf::from(format!("/proc/{}", child.id()));
+        let preload = preload.to_string_lossy().into_owned();
+        // The child's maps only show the library once it has started the new program
+        let started = Instant::now();
+        while !fs::read_to_string(proc_dir.join("maps"))
+            .unwrap()
+            .contains(&preload)
+        {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "never preloaded"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut checker = ProcessChecker::new(&proc_dir);
+        let findings = checker.check().unwrap();
+        assert_eq!(
+            findings,
+            vec![
+                Finding::new(
+                    TamperKind::LoaderVariableSet,
+                    format!("LD_PRELOAD={}", preload)
+                ),
+                Finding::new(TamperKind::UnexpectedLibrary, preload),
+            ]
+        );
+        assert_eq!(checker.check().unwrap(), vec![]);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
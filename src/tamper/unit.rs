@@ -0,0 +1,405 @@
+//! Checks of the daemon's systemd service unit.
+//!
+//! The effective configuration is resolved the way systemd does it: the unit file comes from the
+//! first search path which has one, and drop-ins (`<unit>.d/*.conf` and `service.d/*.conf`) from
+//! every search path are applied on top in file name order, a drop-in in an earlier search path
+//! hiding one of the same name in a later one. So a drop-in which turns off `Restart` is caught
+//! just like an edit of the unit file itself.
+
+use crate::tamper::{Finding, TamperKind};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The system unit search paths, highest priority first
+const SYSTEM_UNIT_PATHS: [&str; 11] = [
+    "/etc/systemd/system.control",
+    "/run/systemd/system.control",
+    "/run/systemd/transient",
+    "/etc/systemd/system",
+    "/etc/systemd/system.attached",
+    "/run/systemd/system",
+    "/run/systemd/system.attached",
+    "/run/systemd/generator",
+    "/usr/local/lib/systemd/system",
+    "/usr/lib/systemd/system",
+    "/lib/systemd/system",
+];
+
+const EXPECTED_RESTART: &str = "always";
+const EXPECTED_RESTART_SEC: &str = "30s";
+
+/// Drop-ins in this directory apply to every service
+const ALL_SERVICES_DROP_IN_DIR: &str = "service.d";
+
+/// Characters which may prefix an `ExecStart` command to change how it is run
+const EXEC_PREFIXES: &[char] = &['@', '-', ':', '+', '!'];
+
+/// Where systemd looks for units
+pub(crate) struct UnitPaths {
+    dirs: Vec<PathBuf>,
+}
+
+impl UnitPaths {
+    pub(crate) fn system() -> Self {
+        Self::new(SYSTEM_UNIT_PATHS.iter().map(PathBuf::from).collect())
+    }
+
+    /// `dirs` are searched in order, so the first has the highest priority
+    pub(crate) fn new(dirs: Vec<PathBuf>) -> Self {
+        Self { dirs }
+    }
+
+    /// Every path the unit file for `unit` may be at, highest priority first
+    pub(crate) fn unit_files(&self, unit: &str) -> Vec<PathBuf> {
+        self.dirs.iter().map(|dir| dir.join(unit)).collect()
+    }
+
+    /// The unit file for `unit`, if any search path has one
+    fn unit_file(&self, unit: &str) -> Option<PathBuf> {
+        self.unit_files(unit)
+            .into_iter()
+            .find(|path| path.symlink_metadata().is_ok())
+    }
+
+    /// Every directory drop-ins for `unit` may be placed in, whether it exists or not
+    pub(crate) fn drop_in_dirs(&self, unit: &str) -> Vec<PathBuf> {
+        self.dirs
+            .iter()
+            .flat_map(|dir| {
+                [
+                    dir.join(format!("{}.d", unit)),
+                    dir.join(ALL_SERVICES_DROP_IN_DIR),
+                ]
+            })
+            .collect()
+    }
+
+    /// The drop-ins which apply to `unit`, in the order they are applied
+    fn drop_ins(&self, unit: &str) -> Vec<PathBuf> {
+        // Keyed by file name, so that the first search path to have a name wins and the rest are
+        // applied in name order
+        let mut drop_ins = BTreeMap::new();
+        for dir in self.drop_in_dirs(unit) {
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.filter_map(Result::ok) {
+                let name = entry.file_name();
+                if Path::new(&name)
+                    .extension()
+                    .is_some_and(|ext| ext == "conf")
+                {
+                    drop_ins.entry(name).or_insert_with(|| entry.path());
+                }
+            }
+        }
+        drop_ins.into_values().collect()
+    }
+
+    /// Whether `unit` is wanted or required by some target, as `systemctl enable` sets up
+    fn is_enabled(&self, unit: &str) -> bool {
+        self.dirs.iter().any(|dir| {
+            fs::read_dir(dir).is_ok_and(|entries| {
+                entries.filter_map(Result::ok).any(|entry| {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    (name.ends_with(".wants") || name.ends_with(".requires"))
+                        && entry.path().join(unit).symlink_metadata().is_ok()
+                })
+            })
+        })
+    }
+}
+
+/// A setting and the file which set it
+#[derive(Debug, Clone)]
+struct Setting {
+    value: String,
+    file: PathBuf,
+}
+
+/// The `[Service]` settings which the checks look at, after applying all drop-ins
+#[derive(Debug, Default)]
+struct ServiceSettings {
+    exec_start: Vec<Setting>,
+    restart: Option<Setting>,
+    restart_sec: Option<Setting>,
+}
+
+impl ServiceSettings {
+    fn apply(&mut self, file: &Path, contents: &str) {
+        for (section, key, value) in parse_unit(contents) {
+            if section != "Service" {
+                continue;
+            }
+            let setting = Setting {
+                value,
+                file: file.to_path_buf(),
+            };
+            match key.as_str() {
+                // An empty assignment clears the commands set so far
+                "ExecStart" if setting.value.is_empty() => self.exec_start.clear(),
+                "ExecStart" => self.exec_start.push(setting),
+                "Restart" => self.restart = Some(setting),
+                "RestartSec" => self.restart_sec = Some(setting),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The `(section, key, value)` assignments in a unit file
+fn parse_unit(contents: &str) -> Vec<(String, String, String)> {
+    let mut assignments = Vec::new();
+    let mut section = String::new();
+    let mut lines = contents.lines();
+    while let Some(line) = lines.next() {
+        let mut line = line.trim().to_string();
+        // A trailing backslash continues the line
+        while line.ends_with('\\') {
+            line.pop();
+            line.push(' ');
+            match lines.next() {
+                Some(next) => line.push_str(next.trim()),
+                None => break,
+            }
+        }
+
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.to_string();
+        } else if let Some((key, value)) = line.split_once('=') {
+            assignments.push((
+                section.clone(),
+                key.trim().to_string(),
+                value.trim().to_string(),
+            ));
+        }
+    }
+    assignments
+}
+
+/// `RestartSec` in seconds, which may be given with or without the `s` unit
+fn restart_sec_matches(value: &str, expected: &str) -> bool {
+    let seconds = |value: &str| value.trim_end_matches('s').trim().to_string();
+    seconds(value) == seconds(expected)
+}
+
+/// Checks that `unit` is installed, enabled, keeps restarting and runs `exe`
+pub(crate) fn check_unit(paths: &UnitPaths, unit: &str, exe: &Path) -> Vec<Finding> {
+    let Some(unit_file) = paths.unit_file(unit) else {
+        return vec![Finding::new(
+            TamperKind::UnitMissing,
+            format!("{} was not found", unit),
+        )];
+    };
+    if fs::canonicalize(&unit_file).is_ok_and(|target| target == Path::new("/dev/null")) {
+        return vec![Finding::new(
+            TamperKind::UnitMasked,
+            format!("{:?} links to /dev/null", unit_file),
+        )];
+    }
+
+    let mut findings = Vec::new();
+    if !paths.is_enabled(unit) {
+        findings.push(Finding::new(
+            TamperKind::UnitDisabled,
+            format!("{} is not wanted by any target", unit),
+        ));
+    }
+
+    let mut settings = ServiceSettings::default();
+    for file in [unit_file].into_iter().chain(paths.drop_ins(unit)) {
+        match fs::read_to_string(&file) {
+            Ok(contents) => settings.apply(&file, &contents),
+            Err(e) => warn!("Failed to read {:?}: {}", file, e),
+        }
+    }
+
+    let exe = fs::canonicalize(exe).unwrap_or_else(|_| exe.to_path_buf());
+    match settings.exec_start.as_slice() {
+        [command] => {
+            let program = command
+                .value
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .trim_start_matches(EXEC_PREFIXES);
+            if fs::canonicalize(program).map_or(true, |program| program != exe) {
+                findings.push(Finding::new(
+                    TamperKind::ExecStartMismatch,
+                    format!(
+                        "ExecStart={} (set in {:?}) doesn't run {:?}",
+                        command.value, command.file, exe
+                    ),
+                ));
+            }
+        }
+        commands => findings.push(Finding::new(
+            TamperKind::ExecStartMismatch,
+            format!("Expected one ExecStart, found {:?}", commands),
+        )),
+    }
+
+    match &settings.restart {
+        Some(restart) if restart.value == EXPECTED_RESTART => {}
+        restart => findings.push(Finding::new(
+            TamperKind::RestartChanged,
+            match restart {
+                Some(restart) => format!("Restart={} (set in {:?})", restart.value, restart.file),
+                None => "Restart is not set".to_string(),
+            },
+        )),
+    }
+    match &settings.restart_sec {
+        Some(sec) if restart_sec_matches(&sec.value, EXPECTED_RESTART_SEC) => {}
+        sec => findings.push(Finding::new(
+            TamperKind::RestartDelayChanged,
+            match sec {
+                Some(sec) => format!("RestartSec={} (set in {:?})", sec.value, sec.file),
+                None => "RestartSec is not set".to_string(),
+            },
+        )),
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_dir;
+    use pretty_assertions::assert_eq;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    const UNIT: &str = "open-accountability.service";
+
+    /// Two search paths, like `/etc/systemd/system` and `/usr/lib/systemd/system`, holding a
+    /// correctly installed unit which runs `exe`
+    struct Fixture {
+        _root: TempDir,
+        etc: PathBuf,
+        lib: PathBuf,
+        exe: PathBuf,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let root = temp_dir();
+            let etc = root.path().join("etc");
+            let lib = root.path().join("lib");
+            let exe = root.path().join("open-accountability");
+            fs::create_dir_all(etc.join("multi-user.target.wants")).unwrap();
+            fs::create_dir_all(&lib).unwrap();
+            fs::write(&exe, "").unwrap();
+            fs::write(
+                lib.join(UNIT),
+                format!(
+                    "[Unit]\nDescription=OpenAccountability\n\n[Service]\n\
+                     ExecStart={} \\\n  --device-code\nRestart=always\nRestartSec=30s\n\n\
+                     [Install]\nWantedBy=multi-user.target\n",
+                    exe.display()
+                ),
+            )
+            .unwrap();
+            symlink(
+                lib.join(UNIT),
+                etc.join("multi-user.target.wants").join(UNIT),
+            )
+            .unwrap();
+            Self {
+                _root: root,
+                etc,
+                lib,
+                exe,
+            }
+        }
+
+        fn check(&self) -> Vec<TamperKind> {
+            let paths = UnitPaths::new(vec![self.etc.clone(), self.lib.clone()]);
+            check_unit(&paths, UNIT, &self.exe)
+                .into_iter()
+                .map(|finding| finding.kind)
+                .collect()
+        }
+
+        fn drop_in(&self, dir: &Path, name: &str, contents: &str) {
+            let dir = dir.join(format!("{}.d", UNIT));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(name), contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_installed_unit_passes() {
+        let fixture = Fixture::new();
+        assert_eq!(fixture.check(), vec![]);
+
+        // Drop-ins which leave the checked settings alone are fine
+        fixture.drop_in(&fixture.etc, "env.conf", "[Service]\nEnvironment=A=1\n");
+        assert_eq!(fixture.check(), vec![]);
+    }
+
+    #[test]
+    fn test_drop_in_overrides() {
+        let fixture = Fixture::new();
+        fixture.drop_in(&fixture.lib, "50-restart.conf", "[Service]\nRestart=no\n");
+        assert_eq!(fixture.check(), vec![TamperKind::RestartChanged]);
+
+        // A drop-in of the same name in a higher priority path hides the lower one
+        fixture.drop_in(&fixture.etc, "50-restart.conf", "[Service]\n");
+        assert_eq!(fixture.check(), vec![]);
+
+        // Drop-ins for all services count too
+        fs::create_dir_all(fixture.etc.join("service.d")).unwrap();
+        fs::write(
+            fixture.etc.join("service.d/10-delay.conf"),
+            "[Service]\nRestartSec=1h\n",
+        )
+        .unwrap();
+        assert_eq!(fixture.check(), vec![TamperKind::RestartDelayChanged]);
+    }
+
+    #[test]
+    fn test_exec_start_replaced() {
+        let fixture = Fixture::new();
+        fixture.drop_in(
+            &fixture.etc,
+            "exec.conf",
+            "[Service]\nExecStart=\nExecStart=/bin/sleep infinity\n",
+        );
+        assert_eq!(fixture.check(), vec![TamperKind::ExecStartMismatch]);
+    }
+
+    #[test]
+    fn test_masked_and_disabled() {
+        let fixture = Fixture::new();
+        fs::remove_file(fixture.etc.join("multi-user.target.wants").join(UNIT)).unwrap();
+        assert_eq!(fixture.check(), vec![TamperKind::UnitDisabled]);
+
+        symlink("/dev/null", fixture.etc.join(UNIT)).unwrap();
+        assert_eq!(fixture.check(), vec![TamperKind::UnitMasked]);
+
+        fs::remove_file(fixture.etc.join(UNIT)).unwrap();
+        fs::remove_file(fixture.lib.join(UNIT)).unwrap();
+        assert_eq!(fixture.check(), vec![TamperKind::UnitMissing]);
+    }
+
+    #[test]
+    fn test_parse_unit() {
+        assert_eq!(
+            parse_unit("# comment\n[Service]\nExecStart=/bin/a \\\n  --flag\n; other\nKey = v\n"),
+            vec![
+                (
+                    "Service".to_string(),
+                    "ExecStart".to_string(),
+                    "/bin/a  --flag".to_string()
+                ),
+                ("Service".to_string(), "Key".to_string(), "v".to_string()),
+            ]
+        );
+    }
+}
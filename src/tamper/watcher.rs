@@ -0,0 +1,365 @@
+//! Watches the files the daemon depends on, reporting changes to them as they happen.
+//!
+//! Files are watched through their parent directories, so that a file which is replaced,
+//! deleted or created later is still seen. Drop-in directories are watched as a whole, since
+//! any file added to them changes the service unit.
+
+use crate::queue::{EventQueue, QueuedEvent};
+use crate::tamper::unit::UnitPaths;
+use crate::tamper::{Finding, TamperKind};
+use futures_util::StreamExt;
+use inotify::{EventMask, EventOwned, EventStream, Inotify, WatchDescriptor, WatchMask, Watches};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A burst of events for the same change, like the writes of one edit, is reported once
+const DEBOUNCE: Duration = Duration::from_secs(5);
+
+/// How long after the daemon replaces a file its rename is expected to be seen
+const OWN_RENAME_WINDOW: Duration = Duration::from_secs(5);
+
+/// Files the daemon has just replaced by renaming a new one over them, and when
+static OWN_RENAMES: Mutex<Vec<(PathBuf, Instant)>> = Mutex::new(Vec::new());
+
+/// Notes that the daemon is about to rename a new file over `path`, so that the rename isn't
+/// reported. Renames made by anything else are still reported.
+pub(crate) fn expect_own_rename(path: &Path) {
+    let mut renames = OWN_RENAMES.lock().unwrap();
+    renames.retain(|(_, at)| at.elapsed() < OWN_RENAME_WINDOW);
+    renames.push((path.to_path_buf(), Instant::now()));
+}
+
+/// Whether the daemon itself just renamed a file over `path`. Each expected rename is only
+/// matched once.
+fn take_own_rename(path: &Path) -> bool {
+    let mut renames = OWN_RENAMES.lock().unwrap();
+    renames.retain(|(_, at)| at.elapsed() < OWN_RENAME_WINDOW);
+    match renames.iter().position(|(renamed, _)| renamed == path) {
+        Some(index) => {
+            renames.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+const EVENT_BUFFER_SIZE: usize = 4096;
+
+/// A file or directory whose changes are reported
+#[derive(Debug, Clone)]
+pub(crate) struct Watched {
+    pub(crate) path: PathBuf,
+    /// What the file is, for the report
+    pub(crate) role: &'static str,
+    /// Changes to any entry of the directory are reported too
+    pub(crate) is_dir: bool,
+    /// The daemon replaces the file itself by renaming a new one over it, which isn't reported as
+    /// long as it went through [`expect_own_rename`]
+    pub(crate) rewritten_by_daemon: bool,
+}
+
+impl Watched {
+    fn file(path: impl Into<PathBuf>, role: &'static str) -> Self {
+        Self {
+            path: path.into(),
+            role,
+            is_dir: false,
+            rewritten_by_daemon: false,
+        }
+    }
+}
+
+/// The files the daemon depends on: its service unit wherever systemd may find it, the unit's
+/// drop-in directories, its binary, its device file, and tesseract's trained data
+pub(crate) fn daemon_files(
+    paths: &UnitPaths,
+    unit: &str,
+    exe: &Path,
+    device_path: &Path,
+    traineddata: Option<PathBuf>,
+) -> Vec<Watched> {
+    let mut files: Vec<Watched> = paths
+        .unit_files(unit)
+        .into_iter()
+        .map(|path| Watched::file(path, "service unit"))
+        .collect();
+    files.extend(paths.drop_in_dirs(unit).into_iter().map(|path| Watched {
+        is_dir: true,
+        ..Watched::file(path, "service unit drop-ins")
+    }));
+    files.push(Watched::file(exe, "daemon binary"));
+    files.push(Watched {
+        rewritten_by_daemon: true,
+        ..Watched::file(device_path, "device file")
+    });
+    files.extend(traineddata.map(|path| Watched::file(path, "OCR trained data")));
+    files
+}
+
+/// A watched directory, and what in it is watched
+#[derive(Default)]
+struct WatchedDir {
+    path: PathBuf,
+    entries: HashMap<OsString, Watched>,
+    /// Set when every entry is watched
+    all: Option<Watched>,
+}
+
+pub(crate) struct FileWatcher {
+    events: EventStream<[u8; EVENT_BUFFER_SIZE]>,
+    watches: Watches,
+    dirs: HashMap<WatchDescriptor, WatchedDir>,
+    /// When each change was last reported
+    reported: HashMap<(String, TamperKind), Instant>,
+}
+
+impl FileWatcher {
+    /// Watches `files`, skipping any whose directory doesn't exist
+    pub(crate) fn new(files: Vec<Watched>) -> io::Result<Self> {
+        let inotify = Inotify::init()?;
+        let watches = inotify.watches();
+        let mut watcher = Self {
+            events: inotify.into_event_stream([0; EVENT_BUFFER_SIZE])?,
+            watches,
+            dirs: HashMap::new(),
+            reported: HashMap::new(),
+        };
+        for file in files {
+            watcher.add(file)?;
+        }
+        Ok(watcher)
+    }
+
+    fn watch_dir(&mut self, dir: &Path) -> io::Result<&mut WatchedDir> {
+        let mask = WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MODIFY
+            | WatchMask::ATTRIB
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO;
+        let wd = self.watches.add(dir, mask)?;
+        let watched_dir = self.dirs.entry(wd).or_default();
+        watched_dir.path = dir.to_path_buf();
+        Ok(watched_dir)
+    }
+
+    fn add(&mut self, file: Watched) -> io::Result<()> {
+        if let (Some(parent), Some(name)) = (file.path.parent(), file.path.file_name()) {
+            if parent.is_dir() {
+                let name = name.to_os_string();
+                self.watch_dir(parent)?.entries.insert(name, file.clone());
+            }
+        }
+        if file.is_dir && file.path.is_dir() {
+            let path = file.path.clone();
+            self.watch_dir(&path)?.all = Some(file);
+        }
+        Ok(())
+    }
+
+    /// What `event` means for the watched files, if anything
+    fn finding(&mut self, event: EventOwned) -> Option<Finding> {
+        let dir = self.dirs.get(&event.wd)?;
+        let name = event.name?;
+        let path = dir.path.join(&name);
+        let (file, is_entry) = match (dir.entries.get(&name), &dir.all) {
+            (Some(file), _) => (file.clone(), false),
+            (None, Some(dir_file)) => (dir_file.clone(), true),
+            (None, None) => return None,
+        };
+
+        let deleted = event
+            .mask
+            .intersects(EventMask::DELETE | EventMask::MOVED_FROM);
+        if file.rewritten_by_daemon
+            && event.mask.contains(EventMask::MOVED_TO)
+            && take_own_rename(&path)
+        {
+            return None;
+        }
+        // A drop-in directory which was just created is watched from now on
+        if file.is_dir && !is_entry && !deleted && path.is_dir() {
+            if let Err(e) = self.add(file.clone()) {
+                warn!("Failed to watch {:?}: {}", path, e);
+            }
+        }
+
+        let kind = match deleted {
+            true => TamperKind::FileDeleted,
+            false => TamperKind::FileModified,
+        };
+        Some(Finding::new(kind, format!("{} {:?}", file.role, path)))
+    }
+
+    /// Waits for the next change to a watched file
+    pub(crate) async fn next_change(&mut self) -> io::Result<Finding> {
+        loop {
+            let event = self
+                .events
+                .next()
+                .await
+                .ok_or_else(|| io::Error::other("inotify stream ended"))??;
+            let Some(finding) = self.finding(event) else {
+                continue;
+            };
+
+            let key = (finding.detail.clone(), finding.kind);
+            let now = Instant::now();
+            if self
+                .reported
+                .get(&key)
+                .is_some_and(|&at| now.duration_since(at) < DEBOUNCE)
+            {
+                continue;
+            }
+            self.reported.insert(key, now);
+            return Ok(finding);
+        }
+    }
+}
+
+/// Queues a tamper event for each change to the watched files, for as long as the program runs
+pub(crate) async fn run_file_watcher(mut watcher: FileWatcher, queue: EventQueue) {
+    loop {
+        let finding = match watcher.next_change().await {
+            Ok(finding) => finding,
+            Err(e) => {
+                error!("Stopped watching files: {}", e);
+                return;
+            }
+        };
+        warn!("Watched file changed: {}", finding);
+        if let Err(e) = queue.push(&QueuedEvent::new(SystemTime::now(), finding.into_event())) {
+            error!("Failed to queue tamper event: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_dir;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    async fn next_change(watcher: &mut FileWatcher) -> (TamperKind, String) {
+        let finding = tokio::time::timeout(Duration::from_secs(5), watcher.next_change())
+            .await
+            .expect("no change was reported")
+            .unwrap();
+        (finding.kind, finding.detail)
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_reported() {
+        let temp = temp_dir();
+        let dir = temp.path();
+        let unit = dir.join("test.service");
+        let device = dir.join("device");
+        let drop_ins = dir.join("test.service.d");
+        fs::write(&unit, "[Service]\n").unwrap();
+        fs::write(&device, "credentials").unwrap();
+
+        let mut watcher = FileWatcher::new(vec![
+            Watched::file(&unit, "service unit"),
+            Watched {
+                rewritten_by_daemon: true,
+                ..Watched::file(&device, "device file")
+            },
+            Watched {
+                is_dir: true,
+                ..Watched::file(&drop_ins, "service unit drop-ins")
+            },
+        ])
+        .unwrap();
+
+        // Unwatched files and the daemon's own rewrites of the device file are ignored
+        fs::write(dir.join("other"), "").unwrap();
+        fs::write(dir.join(".device.tmp"), "new credentials").unwrap();
+        expect_own_rename(&device);
+        fs::rename(dir.join(".device.tmp"), &device).unwrap();
+
+        fs::write(&unit, "[Service]\nRestart=no\n").unwrap();
+        assert_eq!(
+            next_change(&mut watcher).await,
+            (TamperKind::FileModified, format!("service unit {:?}", unit))
+        );
+
+        // A drop-in directory created later is watched too
+        fs::create_dir(&drop_ins).unwrap();
+        assert_eq!(
+            next_change(&mut watcher).await,
+            (
+                TamperKind::FileModified,
+                format!("service unit drop-ins {:?}", drop_ins)
+            )
+        );
+        let drop_in = drop_ins.join("override.conf");
+        fs::write(&drop_in, "[Service]\n").unwrap();
+        assert_eq!(
+            next_change(&mut watcher).await,
+            (
+                TamperKind::FileModified,
+                format!("service unit drop-ins {:?}", drop_in)
+            )
+        );
+
+        // Anything else renaming a file over the device file is reported
+        fs::write(dir.join(".device.tmp"), "other credentials").unwrap();
+        fs::rename(dir.join(".device.tmp"), &device).unwrap();
+        assert_eq!(
+            next_change(&mut watcher).await,
+            (
+                TamperKind::FileModified,
+                format!("device file {:?}", device)
+            )
+        );
+
+        fs::remove_file(&device).unwrap();
+        assert_eq!(
+            next_change(&mut watcher).await,
+            (TamperKind::FileDeleted, format!("device file {:?}", device))
+        );
+    }
+
+    #[test]
+    fn test_daemon_files() {
+        let paths = UnitPaths::new(vec![PathBuf::from("/etc"), PathBuf::from("/lib")]);
+        let files = daemon_files(
+            &paths,
+            "test.service",
+            Path::new("/usr/bin/test"),
+            Path::new("/var/lib/test/device"),
+            None,
+        );
+        let roles: Vec<(&str, PathBuf)> = files
+            .iter()
+            .map(|file| (file.role, file.path.clone()))
+            .collect();
+        assert_eq!(
+            roles,
+            vec![
+                ("service unit", PathBuf::from("/etc/test.service")),
+                ("service unit", PathBuf::from("/lib/test.service")),
+                (
+                    "service unit drop-ins",
+                    PathBuf::from("/etc/test.service.d")
+                ),
+                ("service unit drop-ins", PathBuf::from("/etc/service.d")),
+                (
+                    "service unit drop-ins",
+                    PathBuf::from("/lib/test.service.d")
+                ),
+                ("service unit drop-ins", PathBuf::from("/lib/service.d")),
+                ("daemon binary", PathBuf::from("/usr/bin/test")),
+                ("device file", PathBuf::from("/var/lib/test/device")),
+            ]
+        );
+        assert!(files[7].rewritten_by_daemon);
+    }
+}
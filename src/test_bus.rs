@@ -0,0 +1,47 @@
+//! A private D-Bus session bus for tests, so that mock services never touch the real session.
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use zbus::{Connection, ConnectionBuilder};
+
+pub(crate) struct TestBus {
+    daemon: Child,
+    pub(crate) address: String,
+}
+
+impl TestBus {
+    /// Starts a `dbus-daemon` for the test. Fails the test if it isn't installed, rather than
+    /// letting it pass without running.
+    pub(crate) fn start() -> Self {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("dbus-daemon is needed for this test");
+
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .expect("dbus-daemon didn't print its address");
+        Self {
+            daemon,
+            address: address.trim().to_string(),
+        }
+    }
+
+    pub(crate) async fn connect(&self) -> Connection {
+        ConnectionBuilder::address(self.address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap()
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
@@ -0,0 +1,23 @@
+//! Fixtures shared by the tests of several modules.
+
+use crate::auth::register::Device;
+use tempfile::TempDir;
+
+/// A directory for one test, removed when dropped even if the test fails
+pub(crate) fn temp_dir() -> TempDir {
+    tempfile::Builder::new()
+        .prefix("open-accountability-")
+        .tempdir()
+        .unwrap()
+}
+
+/// A registered device with placeholder credentials
+pub(crate) fn test_device() -> Device {
+    Device {
+        refresh_token: "test_refresh_token".to_string(),
+        id_token: "test_id_token".to_string(),
+        uuid: "test_device_uid".to_string(),
+        name: "".to_string(),
+        safe_shutdown_id: "".to_string(),
+    }
+}